@@ -0,0 +1,36 @@
+//! Bundles the WASI stage 2 loader for `builtin:wasi`, as `wasi-loader/build.js` does with esbuild.
+//!
+//! The loader uses the names that `wasi-loader/imports.js` provides without importing them. Here
+//! they are defined ahead of it instead: the WASI environment by `wasi-shim.js`, and `load_config`
+//! by the instruction stream that the interpreter of `wasi-loader/interpret` makes of
+//! `config.toml`. The files are concatenated as they are.
+use std::{env, fs, path::Path};
+
+const STAGE2: &str = "wasi-loader/stage2-wasi.js";
+const SHIM: &str = "wasi-loader/wasi-shim.js";
+const CONFIG: &str = "wasi-loader/config.toml";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    for input in [STAGE2, SHIM, CONFIG] {
+        println!("cargo:rerun-if-changed={input}");
+    }
+
+    let stage2 = fs::read_to_string(STAGE2)?;
+    let shim = fs::read_to_string(SHIM)?;
+    let stream = wasi_loader::interpret(&fs::read(CONFIG)?);
+
+    // As the module that `build.js` synthesizes for the configuration.
+    let bytes: Vec<String> = stream.iter().map(u8::to_string).collect();
+    let config = format!(
+        "async function load_config() {{\n  \
+           const data = \"{}\".split(\",\");\n  \
+           return Uint8Array.from(data, c => parseInt(c, 10));\n\
+         }}\n",
+        bytes.join(","),
+    );
+
+    let bundle = [shim, config, stage2].join("\n");
+    let out = Path::new(&env::var("OUT_DIR")?).join("builtin-wasi.js");
+    fs::write(out, bundle)?;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! A binary-to-text encoding that is denser than base64 and safe within HTML text.
+//!
+//! Modelled after [base122](https://blog.kevinalbs.com/base122). The input is read as a stream of
+//! 7-bit groups, most significant bit first, and each group becomes one ASCII character. Groups
+//! the HTML parser would not pass through unchanged as template text are *illegal*: `NUL` is
+//! replaced, `CR` is normalized and `&` and `<` start character references and tags. An illegal
+//! group is combined with the group following it into a single two-byte UTF-8 character:
+//!
+//! * `((index + 1) << 7) | next`, in `U+0080..U+0280`, for the illegal group at `index` followed
+//!   by the group `next`.
+//! * `LAST + index`, for an illegal group that is the last one of the input.
+//!
+//! The last group is padded with zero bits. The decoder drops any bits that do not form a whole
+//! byte. This costs about 8 bits of output for every 7 bits of input, compared to 8 for 6 of
+//! base64, but requires that the document is interpreted as UTF-8.
+
+/// The 7-bit groups that must not appear as characters.
+pub const ILLEGAL: [u8; 4] = [0x00, b'\r', b'&', b'<'];
+
+/// The code point marking an illegal group that ends the input.
+const LAST: u32 = 0x300;
+
+/// A character in the text is not part of the encoding.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// The offset of the character in the text, in bytes.
+    pub offset: usize,
+}
+
+/// Encode arbitrary bytes as text.
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() + data.len() / 7 + 1);
+    let mut groups = groups(data);
+
+    while let Some(group) = groups.next() {
+        let Some(index) = ILLEGAL.iter().position(|&illegal| illegal == group) else {
+            out.push(char::from(group));
+            continue;
+        };
+
+        let code = match groups.next() {
+            Some(next) => ((index as u32 + 1) << 7) | u32::from(next),
+            None => LAST + index as u32,
+        };
+
+        out.push(char::from_u32(code).unwrap());
+    }
+
+    out
+}
+
+/// Decode text that was produced by [`encode`].
+pub fn decode(text: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::with_capacity(text.len() * 7 / 8 + 1);
+    let mut acc = 0u16;
+    let mut bits = 0;
+
+    let mut push = |group: u8| {
+        acc = (acc << 7) | u16::from(group);
+        bits += 7;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    };
+
+    for (offset, ch) in text.char_indices() {
+        let code = u32::from(ch);
+        let illegal = |index: u32| ILLEGAL.get(index as usize).copied();
+
+        match code {
+            0..=0x7f => push(code as u8),
+            LAST.. => push(illegal(code - LAST).ok_or(DecodeError { offset })?),
+            _ => {
+                push(illegal((code >> 7) - 1).ok_or(DecodeError { offset })?);
+                push((code & 0x7f) as u8);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Split the bits of the input into groups of 7, padding the last one with zeros.
+fn groups(data: &[u8]) -> impl Iterator<Item = u8> {
+    let mut groups = Vec::with_capacity(data.len() * 8 / 7 + 1);
+    let mut acc = 0u16;
+    let mut bits = 0;
+
+    for &byte in data {
+        acc = (acc << 8) | u16::from(byte);
+        bits += 8;
+
+        while bits >= 7 {
+            bits -= 7;
+            groups.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    if bits > 0 {
+        groups.push((acc << (7 - bits)) as u8);
+    }
+
+    groups.into_iter()
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Invalid base122 character at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) {
+        let text = encode(data);
+
+        assert!(
+            !text.bytes().any(|b| ILLEGAL.contains(&b)),
+            "encoding of {data:?} contains an illegal character",
+        );
+
+        assert_eq!(decode(&text).unwrap(), data, "encoded as {text:?}");
+    }
+
+    #[test]
+    fn every_byte_value() {
+        let all: Vec<u8> = (0..=255).collect();
+        roundtrip(&all);
+
+        for byte in 0..=255u8 {
+            // At every length, so every byte value ends up in every position of a group,
+            // including the last one of the input.
+            for len in 1..=8 {
+                roundtrip(&vec![byte; len]);
+            }
+
+            roundtrip(&[0xff, byte]);
+            roundtrip(&[byte, 0x00, byte]);
+        }
+    }
+
+    #[test]
+    fn every_prefix() {
+        let all: Vec<u8> = (0..=255).rev().chain(0..=255).collect();
+
+        for len in 0..all.len() {
+            roundtrip(&all[..len]);
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_characters() {
+        assert_eq!(decode("ab\u{400}").unwrap_err().offset, 2);
+        assert_eq!(decode("\u{304}").unwrap_err().offset, 0);
+    }
+}
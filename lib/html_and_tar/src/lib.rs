@@ -1,5 +1,7 @@
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 
+pub mod base122;
+
 mod bytemuck {
     pub fn bytes_of(tar: &super::TarHeader) -> &[u8] {
         let len = core::mem::size_of_val(tar);
@@ -34,6 +36,16 @@ pub struct TarHeader {
     __padding: [u8; 12],
 }
 
+/// How file contents are encoded to be HTML safe.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Standard base64, which is plain ASCII.
+    #[default]
+    Base64,
+    /// A denser encoding, see [`base122`]. Requires the HTML document to be UTF-8.
+    Base122,
+}
+
 pub struct Entry<'la> {
     /// An ascii name for this file.
     pub name: &'la str,
@@ -110,59 +122,51 @@ impl TarEngine {
         name
     }
 
-    pub fn escaped_insert_base64(&mut self, Entry { name, data }: Entry) -> EscapedData {
-        let qualname = Self::qualify_name_for_html_attribute(name);
-
-        let padding = self.pad_to_fit();
-        let data = STANDARD.encode(data).into_bytes();
-
-        const START: &[u8] = b"\0<template class=\"wah_polyglot_data\" __A=\"";
-        const DATA_START: &[u8] = b"\">";
-        const ID: &[u8] = b"\" _wahtml_id=\"";
-        const CONT: &[u8] = b"\" __B=\"";
-
-        let mut this = TarHeader::EMPTY;
-        this.name[..START.len()].copy_from_slice(START);
-        this.assign_size(0);
-        this.assign_standards();
-        let end_start = this.prefix.len() - ID.len();
-        this.prefix[end_start..].copy_from_slice(ID);
-        this.assign_checksum();
-        self.len += core::mem::size_of::<TarHeader>() as u64;
+    pub fn escaped_insert_base64(&mut self, entry: Entry) -> EscapedData {
+        self.escaped_insert(entry, Encoding::Base64)
+    }
 
-        let mut file = TarHeader::EMPTY;
-        let end_start = this.prefix.len() - DATA_START.len();
-        file.name[..qualname.len()].copy_from_slice(qualname.as_bytes());
-        file.name[qualname.len()..][1..][..CONT.len()].copy_from_slice(CONT);
-        file.assign_size(data.len());
-        file.prefix[end_start..].copy_from_slice(DATA_START);
-        file.assign_checksum();
-        self.len += core::mem::size_of::<TarHeader>() as u64;
+    /// Start a sequence of escaped data with an entry whose contents are encoded as given.
+    pub fn escaped_insert(&mut self, entry: Entry, encoding: Encoding) -> EscapedData {
+        self.escaped_entry(b"\0<template", entry, encoding)
+    }
 
-        // Followed by the data.
-        self.len += data.len() as u64;
+    pub fn escaped_continue_base64(&mut self, entry: Entry) -> EscapedData {
+        self.escaped_continue(entry, Encoding::Base64)
+    }
 
-        EscapedData {
-            padding,
-            header: this,
-            file,
-            data,
-        }
+    /// Continue a sequence of escaped data with another entry whose contents are encoded as given.
+    pub fn escaped_continue(&mut self, entry: Entry, encoding: Encoding) -> EscapedData {
+        self.escaped_entry(b"\0</template><template", entry, encoding)
     }
 
-    pub fn escaped_continue_base64(&mut self, Entry { name, data }: Entry) -> EscapedData {
+    fn escaped_entry(
+        &mut self,
+        open: &[u8],
+        Entry { name, data }: Entry,
+        encoding: Encoding,
+    ) -> EscapedData {
         let qualname = Self::qualify_name_for_html_attribute(name);
 
         let padding = self.pad_to_fit();
-        let data = STANDARD.encode(data).into_bytes();
+        let data = encoding.encode(data);
 
-        const START: &[u8] = b"\0</template><template class=\"wah_polyglot_data\" __A=\"";
+        const CLASS: &[u8] = b" class=\"wah_polyglot_data\"";
+        const ATTR: &[u8] = b" __A=\"";
         const DATA_START: &[u8] = b"\">";
         const ID: &[u8] = b"\" _wahtml_id=\"";
         const CONT: &[u8] = b"\" __B=\"";
 
+        // Base64 is the default in the decoder, and needs no attribute.
+        let encoding_attr: &[u8] = match encoding {
+            Encoding::Base64 => b"",
+            Encoding::Base122 => b" data-encoding=\"base122\"",
+        };
+
+        let start = [open, CLASS, encoding_attr, ATTR].concat();
+
         let mut this = TarHeader::EMPTY;
-        this.name[..START.len()].copy_from_slice(START);
+        this.name[..start.len()].copy_from_slice(&start);
         this.assign_size(0);
         this.assign_standards();
         let end_start = this.prefix.len() - ID.len();
@@ -238,6 +242,48 @@ impl TarEngine {
     }
 }
 
+impl Encoding {
+    /// The name used in the `data-encoding` attribute and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Base64 => "base64",
+            Encoding::Base122 => "base122",
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Base64 => STANDARD.encode(data).into_bytes(),
+            Encoding::Base122 => base122::encode(data).into_bytes(),
+        }
+    }
+
+    pub fn decode(self, text: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Encoding::Base64 => Ok(STANDARD.decode(text)?),
+            Encoding::Base122 => Ok(base122::decode(core::str::from_utf8(text)?)?),
+        }
+    }
+}
+
+impl core::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl core::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(Self::Base64),
+            "base122" => Ok(Self::Base122),
+            _ => Err(format!("Unknown encoding {s}")),
+        }
+    }
+}
+
 impl TarHeader {
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::bytes_of(self)
@@ -0,0 +1,393 @@
+//! Builds the data archive from a directory, and places it into a document.
+//!
+//! The archive is meant to be reproducible: entries are added in the order of their byte-wise
+//! sorted paths, with a fixed modification time of 1980-01-01 00:00 (the earliest a zip can
+//! express) and fixed permissions. Only the contents and names of files affect the result.
+use core::error::Error;
+use std::{
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::error::InvalidArchiveError;
+
+const FILE_PERMISSIONS: u32 = 0o644;
+const DIR_PERMISSIONS: u32 = 0o755;
+
+const CENTRAL_DIRECTORY_HEADER: &[u8] = b"PK\x01\x02";
+const END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+
+/// Create a zip archive of all files and directories below `dir`.
+///
+/// Entries are named relative to `dir` with `/` as the separator, the directory itself is not
+/// an entry. Symbolic links are followed.
+pub fn from_dir(dir: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut entries = vec![];
+    collect(dir, "", &mut entries)?;
+
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(DateTime::default());
+
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+
+    for (name, path) in entries {
+        match path {
+            None => zip.add_directory(name, options.unix_permissions(DIR_PERMISSIONS))?,
+            Some(path) => {
+                let data = std::fs::read(&path).map_err(|err| at_path(&path, err))?;
+                zip.start_file(name, options.unix_permissions(FILE_PERMISSIONS))?;
+                zip.write_all(&data)?;
+            }
+        }
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Write the entries of an archive anew, each of them deflated, with the given comment.
+///
+/// Deflating encodes the contents as a bit stream, so markup in a text file does not reappear
+/// verbatim in the archive. Names, modification times and permissions are retained.
+pub fn deflated(zip: &[u8], comment: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(Cursor::new(zip))?;
+    let mut out = ZipWriter::new(Cursor::new(vec![]));
+
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(file.last_modified());
+
+        if file.is_dir() {
+            let mode = file.unix_mode().unwrap_or(DIR_PERMISSIONS);
+            out.add_directory(file.name(), options.unix_permissions(mode))?;
+        } else {
+            let mode = file.unix_mode().unwrap_or(FILE_PERMISSIONS);
+            out.start_file(file.name(), options.unix_permissions(mode))?;
+            std::io::copy(&mut file, &mut out)?;
+        }
+    }
+
+    out.set_comment(comment);
+    Ok(out.finish()?.into_inner())
+}
+
+/// Walk a directory in sorted order, recording directories without a path to read.
+fn collect(
+    dir: &Path,
+    prefix: &str,
+    entries: &mut Vec<(String, Option<PathBuf>)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut children = std::fs::read_dir(dir)
+        .map_err(|err| at_path(dir, err))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| at_path(dir, err))?;
+
+    children.sort();
+
+    for path in children {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("{}: file name is not valid UTF-8", path.display()))?;
+        let name = format!("{prefix}{name}");
+
+        if std::fs::metadata(&path)
+            .map_err(|err| at_path(&path, err))?
+            .is_dir()
+        {
+            let prefix = format!("{name}/");
+            entries.push((prefix.clone(), None));
+            collect(&path, &prefix, entries)?;
+        } else {
+            entries.push((name, Some(path)));
+        }
+    }
+
+    Ok(())
+}
+
+fn at_path(path: &Path, err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
+
+/// Rewrite the offsets in a zip archive for its placement at `base` within a file.
+///
+/// Zip readers locate the end of central directory record from the end of the file and then
+/// follow absolute offsets to the central directory and each local header. An archive embedded in
+/// the last section of a module is only readable if these count from the start of the document,
+/// not from the start of the archive. The current placement is derived from the archive itself,
+/// so an archive that was relocated before can be relocated again.
+pub fn relocate(zip: &mut [u8], base: usize) -> Result<(), InvalidArchiveError> {
+    let eocd = find_end_of_central_directory(zip)
+        .ok_or_else(|| invalid("no end of central directory record"))?;
+
+    if read_u16(zip, eocd + 4) != 0 || read_u16(zip, eocd + 6) != 0 {
+        return Err(invalid("multi-disk archives are not supported"));
+    }
+
+    let entries = read_u16(zip, eocd + 10);
+    let cd_size = read_u32(zip, eocd + 12);
+    let cd_offset = read_u32(zip, eocd + 16);
+
+    if entries == u16::MAX || cd_size == u32::MAX || cd_offset == u32::MAX {
+        return Err(invalid("ZIP64 archives are not supported"));
+    }
+
+    let cd_start = eocd
+        .checked_sub(cd_size as usize)
+        .ok_or_else(|| invalid("the central directory overlaps its end record"))?;
+
+    // Every offset in the archive is shifted by the same amount from its position.
+    let shift = i64::from(cd_offset) - cd_start as i64;
+    let moved = |offset: u32| -> Result<u32, InvalidArchiveError> {
+        let position = i64::from(offset) - shift;
+        if position < 0 || position >= cd_start as i64 {
+            return Err(invalid("a local header offset points outside the archive"));
+        }
+
+        u32::try_from(position + base as i64)
+            .map_err(|_| invalid("the document is too large for offsets without ZIP64"))
+    };
+
+    let mut header = cd_start;
+    for _ in 0..entries {
+        if zip.get(header..header + 4) != Some(CENTRAL_DIRECTORY_HEADER) || header + 46 > eocd {
+            return Err(invalid("a central directory entry is malformed"));
+        }
+
+        let local_offset = read_u32(zip, header + 42);
+        if local_offset == u32::MAX {
+            return Err(invalid("ZIP64 archives are not supported"));
+        }
+
+        write_u32(zip, header + 42, moved(local_offset)?);
+
+        let variable = usize::from(read_u16(zip, header + 28))
+            + usize::from(read_u16(zip, header + 30))
+            + usize::from(read_u16(zip, header + 32));
+        header += 46 + variable;
+    }
+
+    let cd_offset = u32::try_from(cd_start + base)
+        .map_err(|_| invalid("the document is too large for offsets without ZIP64"))?;
+    write_u32(zip, eocd + 16, cd_offset);
+
+    Ok(())
+}
+
+/// Whether the offsets of an archive were rewritten by [`relocate`] to a place other than its
+/// start, as in a packed document.
+pub fn is_relocated(zip: &[u8]) -> bool {
+    let Some(eocd) = find_end_of_central_directory(zip) else {
+        return false;
+    };
+
+    let cd_size = read_u32(zip, eocd + 12) as usize;
+    let cd_offset = read_u32(zip, eocd + 16) as usize;
+    eocd.checked_sub(cd_size)
+        .is_some_and(|cd_start| cd_start != cd_offset)
+}
+
+/// Search backwards for the record, whose comment must extend exactly to the end of the archive.
+fn find_end_of_central_directory(zip: &[u8]) -> Option<usize> {
+    let last = zip.len().checked_sub(END_OF_CENTRAL_DIRECTORY_LEN)?;
+    let first = last.saturating_sub(usize::from(u16::MAX));
+
+    (first..=last).rev().find(|&pos| {
+        zip[pos..].starts_with(END_OF_CENTRAL_DIRECTORY)
+            && usize::from(read_u16(zip, pos + 20)) == last - pos
+    })
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], at: usize, value: u32) {
+    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn invalid(reason: &'static str) -> InvalidArchiveError {
+    InvalidArchiveError {
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a test, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("wasm-as-html-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("index.html", b"<p>Hello</p>"),
+        ("assets/style.css", b"p { color: red }"),
+        ("assets/fonts/empty.woff", b""),
+        ("z.bin", &[0, 1, 2, 3, 255]),
+    ];
+
+    /// Write the files in the given order, each with its own modification time.
+    fn write_tree(dir: &Path, order: impl Iterator<Item = usize>) {
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+
+        for (n, idx) in order.enumerate() {
+            let (name, data) = FILES[idx];
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+
+            let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs((n as u64) << 20);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn from_dir_is_reproducible() {
+        let first = TempDir::new("reproducible-first");
+        let second = TempDir::new("reproducible-second");
+        write_tree(&first.0, 0..FILES.len());
+        write_tree(&second.0, (0..FILES.len()).rev());
+
+        let zip = from_dir(&first.0).unwrap();
+        assert!(zip == from_dir(&first.0).unwrap(), "differs between runs");
+        assert!(zip == from_dir(&second.0).unwrap(), "differs between trees");
+
+        let mut archive = ZipArchive::new(Cursor::new(&zip)).unwrap();
+        let names: Vec<_> = (0..archive.len())
+            .map(|index| archive.by_index(index).unwrap().name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "assets/",
+                "assets/fonts/",
+                "assets/fonts/empty.woff",
+                "assets/style.css",
+                "empty/",
+                "index.html",
+                "z.bin",
+            ]
+        );
+
+        for (name, data) in FILES {
+            let mut file = archive.by_name(name).unwrap();
+            let mut contents = vec![];
+            std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+            assert_eq!(&contents, data, "contents of {name}");
+        }
+    }
+
+    /// An archive with stored and deflated entries, and a directory.
+    fn sample_zip() -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let options = FileOptions::default();
+
+        zip.add_directory("dir/", options).unwrap();
+        zip.start_file(
+            "dir/stored.txt",
+            options.compression_method(CompressionMethod::Stored),
+        )
+        .unwrap();
+        zip.write_all(b"stored contents").unwrap();
+        zip.start_file("deflated.txt", options).unwrap();
+        zip.write_all(&b"deflated contents ".repeat(100)).unwrap();
+        zip.set_comment("with a comment");
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// Check that a file is readable as the archive, with every entry intact.
+    fn assert_same_entries(file: &[u8], zip: &[u8]) {
+        let mut expected = ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut actual = ZipArchive::new(Cursor::new(file)).unwrap();
+        assert_eq!(actual.len(), expected.len());
+
+        for index in 0..expected.len() {
+            let mut expected = expected.by_index(index).unwrap();
+            let mut actual = actual.by_index(index).unwrap();
+            assert_eq!(actual.name(), expected.name());
+
+            let (mut want, mut got) = (vec![], vec![]);
+            std::io::Read::read_to_end(&mut expected, &mut want).unwrap();
+            std::io::Read::read_to_end(&mut actual, &mut got).unwrap();
+            assert!(got == want, "contents of {}", actual.name());
+        }
+    }
+
+    #[test]
+    fn relocated_archive_is_readable() {
+        let zip = sample_zip();
+
+        for base in [0, 1, 4096, 100_000] {
+            let mut file = vec![0xaa; base];
+            file.extend_from_slice(&zip);
+            relocate(&mut file[base..], base).unwrap();
+            assert_same_entries(&file, &zip);
+
+            // Moving it again, as when the document is packed anew.
+            let mut moved = vec![0x55; 7];
+            moved.extend_from_slice(&file[base..]);
+            relocate(&mut moved[7..], 7).unwrap();
+            assert_same_entries(&moved, &zip);
+        }
+    }
+
+    #[test]
+    fn packed_document_is_the_archive() {
+        let zip = sample_zip();
+        let module = b"\0asm\x01\0\0\0";
+
+        for target in [crate::Target::WasmPlusHtml, crate::Target::ShPlusWasm] {
+            for section in [None, Some("renamed_archive")] {
+                let mut packer = crate::Packer::new("export default async function() {}")
+                    .section("wah_polyglot_before", "a section before the archive")
+                    .trailing_zip(zip.clone())
+                    .target(target);
+                if let Some(name) = section {
+                    packer = packer.trailing_zip_section(name);
+                }
+
+                let document = packer.pack(module).unwrap();
+                assert_same_entries(&document, &zip);
+
+                let repacked = crate::Packer::new("export default async function() {}")
+                    .index_html("<p>Moves the archive</p>")
+                    .target(target)
+                    .pack(&document)
+                    .unwrap();
+                assert_same_entries(&repacked, &zip);
+            }
+        }
+    }
+}
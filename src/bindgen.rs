@@ -0,0 +1,474 @@
+//! Recognizes wasm-bindgen glue, and adapts the loaders to the way it takes the module.
+//!
+//! The default export of the glue initializes the module. Since wasm-bindgen 0.2.93 it takes an
+//! object `{ module_or_path }`, before that the module as its `input`. Stage 1 hands stage 2 the
+//! module in either of these conventions, which the packer fills in for the loader at hand.
+//!
+//! Only glue of the `web` target is an ES module that loads the module it is given. The
+//! `no-modules` glue defines a global instead, we export that. Glue of the `bundler`, `nodejs`
+//! and `deno` targets loads its module from a path by itself, which a document can not provide.
+//!
+//! Modules that import from namespaces other than those of wasm-bindgen, such as
+//! `wasi_snapshot_preview1`, get glue that imports these as bare specifiers:
+//!
+//! ```js
+//! import * as __wbg_star0 from 'wasi_snapshot_preview1';
+//! ```
+//!
+//! A page can not resolve those. The packer rewrites such imports into bindings of the glue
+//! module, and exports a hook with which a loader provides them before initializing the module:
+//!
+//! ```js
+//! glue.wah_provide_imports({ wasi_snapshot_preview1: wasi.wasiImport });
+//! await glue.default({ module_or_path });
+//! ```
+use core::error::Error;
+use std::borrow::Cow;
+
+use crate::inspect::find;
+
+/// Where stage 1 and the loader for glue take the convention of stage 2.
+pub const CONVENTION_PLACEHOLDER: &str = "__REPLACE_THIS_WITH_STAGE2_CONVENTION__";
+
+/// How the default export of stage 2 takes the module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Convention {
+    /// As an object `{ module_or_path }`.
+    ModuleOrPath,
+    /// As the argument itself.
+    Input,
+}
+
+/// The function that glue with rewritten imports exports, see the module documentation.
+pub const IMPORT_HOOK: &str = "wah_provide_imports";
+
+/// Glue as it is packed, with the convention of its default export.
+pub struct Glue<'src> {
+    pub convention: Convention,
+    pub source: Cow<'src, [u8]>,
+    /// The bare specifiers that a loader provides through [`IMPORT_HOOK`].
+    pub imports: Vec<String>,
+}
+
+/// An import of a bare specifier, as the glue binds it.
+enum Binding<'src> {
+    /// `import * as local from 'specifier'`
+    Namespace { local: &'src str },
+    /// `import { name as local } from 'specifier'`
+    Named { name: &'src str, local: &'src str },
+}
+
+impl Convention {
+    pub fn name(self) -> &'static str {
+        match self {
+            Convention::ModuleOrPath => "module_or_path",
+            Convention::Input => "input",
+        }
+    }
+
+    /// Fill in the convention for a loader.
+    pub fn apply(self, loader: &[u8]) -> Vec<u8> {
+        let loader = String::from_utf8_lossy(loader);
+        loader
+            .replace(CONVENTION_PLACEHOLDER, self.name())
+            .into_bytes()
+    }
+}
+
+/// Recognize wasm-bindgen glue, returning `None` for other code.
+///
+/// Fails for glue that the document can not load, or whose initialization is not recognized.
+pub fn glue(source: &[u8]) -> Result<Option<Glue<'_>>, Box<dyn Error>> {
+    let mentions = |what: &str| find(source, what.as_bytes()).is_some();
+
+    if !mentions("__wbindgen") && !mentions("__wbg_") {
+        return Ok(None);
+    }
+
+    if mentions("module.exports") || mentions("require(") {
+        Err("the wasm-bindgen glue is of the `nodejs` target, build it with `--target web`")?;
+    }
+
+    if mentions("Deno.") {
+        Err("the wasm-bindgen glue is of the `deno` target, build it with `--target web`")?;
+    }
+
+    let text = String::from_utf8_lossy(source);
+    let imports_module = text
+        .lines()
+        .any(|line| line.trim_start().starts_with("import") && line.contains("_bg.wasm"));
+
+    if imports_module {
+        Err(
+            "the wasm-bindgen glue is of the `bundler` target, which imports the module itself. \
+             Build it with `--target web`",
+        )?;
+    }
+
+    let convention = init_convention(&text)?;
+
+    let source = if mentions("wasm_bindgen = Object.assign(") && !mentions("export default") {
+        // The `no-modules` target assigns its init function to a global, export it instead.
+        let mut module = source.to_vec();
+        module.extend_from_slice(b"\nexport default wasm_bindgen;\n");
+        Cow::Owned(module)
+    } else if mentions("export default") {
+        Cow::Borrowed(source)
+    } else {
+        Err("the wasm-bindgen glue has no default export to initialize the module with")?
+    };
+
+    let mut glue = Glue {
+        convention,
+        source,
+        imports: vec![],
+    };
+
+    rewrite_imports(&mut glue)?;
+    Ok(Some(glue))
+}
+
+/// Rewrite the imports of bare specifiers into bindings that [`IMPORT_HOOK`] assigns.
+///
+/// The glue only reads them when it initializes the module, so binding them later is fine.
+/// wasm-bindgen emits every import on a line of its own, we refuse those spanning several.
+fn rewrite_imports(glue: &mut Glue<'_>) -> Result<(), Box<dyn Error>> {
+    let text = String::from_utf8_lossy(&glue.source);
+    let mut rewritten = String::with_capacity(text.len());
+    let mut bindings = vec![];
+
+    let lines: Vec<_> = text.split_inclusive('\n').collect();
+    for (idx, line) in lines.iter().enumerate() {
+        let Some((clause, specifier)) = parse_import(line) else {
+            // We rewrite imports line by line, one of several lines would remain unresolved.
+            let statement = multi_line_import(&lines[idx..]);
+            if let Some((_, specifier)) = statement.as_deref().and_then(parse_import) {
+                Err(format!(
+                    "the wasm-bindgen glue imports `{specifier}` over several lines, which can not \
+                     be provided. Put the import on one line"
+                ))?;
+            }
+
+            rewritten.push_str(line);
+            continue;
+        };
+
+        let locals: Vec<_> = match parse_clause(clause) {
+            Some(clause) => clause,
+            None => Err(format!(
+                "the wasm-bindgen glue imports `{specifier}` in a way that can not be provided: \
+                 `{}`",
+                line.trim(),
+            ))?,
+        };
+
+        let names: Vec<_> = locals
+            .iter()
+            .map(|binding| match binding {
+                Binding::Namespace { local } | Binding::Named { local, .. } => *local,
+            })
+            .collect();
+
+        rewritten.push_str(&format!("let {};\n", names.join(", ")));
+        bindings.extend(locals.into_iter().map(|binding| (specifier, binding)));
+    }
+
+    if bindings.is_empty() {
+        return Ok(());
+    }
+
+    rewritten.push_str(&format!(
+        "\n/* Provided by the loader, see `src/bindgen.rs` of wasm-as-html. */\n\
+         export function {IMPORT_HOOK}(namespaces) {{\n"
+    ));
+
+    let mut imports: Vec<String> = vec![];
+    for (specifier, binding) in &bindings {
+        let quoted = format!("{specifier:?}");
+        match binding {
+            Binding::Namespace { local } => {
+                rewritten.push_str(&format!("  {local} = namespaces[{quoted}];\n"));
+            }
+            Binding::Named { name, local } => {
+                rewritten.push_str(&format!("  {local} = namespaces[{quoted}][{name:?}];\n"));
+            }
+        }
+
+        if !imports.iter().any(|import| import == specifier) {
+            imports.push(specifier.to_string());
+        }
+    }
+
+    rewritten.push_str("}\n");
+    glue.source = Cow::Owned(rewritten.into_bytes());
+    glue.imports = imports;
+    Ok(())
+}
+
+/// Split a static import of a bare specifier into its clause and the specifier.
+fn parse_import(line: &str) -> Option<(&str, &str)> {
+    let rest = line.trim().strip_prefix("import ")?;
+    let rest = rest.trim_end().trim_end_matches(';');
+    let (clause, specifier) = rest.rsplit_once(" from ")?;
+
+    let specifier = specifier.trim();
+    let quote = specifier
+        .chars()
+        .next()
+        .filter(|&c| c == '\'' || c == '"')?;
+    let specifier = specifier.strip_prefix(quote)?.strip_suffix(quote)?;
+
+    // Relative paths and URLs are no namespaces of the module.
+    let is_path = ["./", "../", "/"]
+        .iter()
+        .any(|prefix| specifier.starts_with(prefix))
+        || specifier.contains(':');
+
+    (!is_path).then_some((clause.trim(), specifier))
+}
+
+/// A static import that starts on the first of the lines and spans several, joined into one.
+fn multi_line_import(lines: &[&str]) -> Option<String> {
+    let first = lines.first()?.trim();
+    let clause = first.strip_prefix("import")?;
+
+    // Not `import(` nor `import.meta`, nor an import without bindings.
+    let opens_clause = clause.starts_with([' ', '{', '*'])
+        && !clause.trim_start().starts_with(['\'', '"'])
+        && !first.contains(" from ");
+    if !opens_clause {
+        return None;
+    }
+
+    let mut statement = String::new();
+    for line in lines {
+        statement.push_str(line.trim());
+        statement.push(' ');
+
+        if line.contains("from ") && line.contains(['\'', '"']) {
+            return Some(statement);
+        }
+    }
+
+    None
+}
+
+/// The bindings of `* as name` or `{ a, b as c }`, which are all that wasm-bindgen emits.
+fn parse_clause(clause: &str) -> Option<Vec<Binding<'_>>> {
+    if let Some(local) = clause.strip_prefix("* as ") {
+        return Some(vec![Binding::Namespace {
+            local: local.trim(),
+        }]);
+    }
+
+    let names = clause.strip_prefix('{')?.strip_suffix('}')?;
+    let bindings = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.split_once(" as ") {
+            Some((name, local)) => Binding::Named {
+                name: name.trim(),
+                local: local.trim(),
+            },
+            None => Binding::Named { name, local: name },
+        })
+        .collect();
+
+    Some(bindings)
+}
+
+/// Read the convention from the first parameter of the init function.
+///
+/// Glue with threads takes the shared memory second, in either convention.
+fn init_convention(text: &str) -> Result<Convention, Box<dyn Error>> {
+    let parameters = ["function __wbg_init(", "function init("]
+        .iter()
+        .find_map(|start| {
+            let at = text.find(start)? + start.len();
+            let end = text[at..].find(')')?;
+            Some(&text[at..at + end])
+        })
+        .ok_or("found no init function in the wasm-bindgen glue")?;
+
+    match parameters.split(',').next().unwrap_or_default().trim() {
+        "module_or_path" => Ok(Convention::ModuleOrPath),
+        "input" => Ok(Convention::Input),
+        other => Err(format!(
+            "the init function of the wasm-bindgen glue takes `{other}`, which is not a known \
+             signature"
+        ))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The init function of wasm-bindgen 0.2.93 and later, with the `web` target.
+    const MODULE_OR_PATH: &str = "\
+let wasm;
+function __wbg_get_imports() { return { wbg: { __wbindgen_throw() {} } }; }
+async function __wbg_init(module_or_path) {
+    if (typeof module_or_path === 'undefined') {
+        module_or_path = new URL('app_bg.wasm', import.meta.url);
+    }
+}
+export { initSync };
+export default __wbg_init;
+";
+
+    /// The init function of earlier releases.
+    const INPUT: &str = "\
+let wasm;
+async function __wbg_init(input) {
+    const imports = __wbg_get_imports();
+}
+export default __wbg_init;
+";
+
+    const NO_MODULES: &str = "\
+let wasm_bindgen;
+(function() {
+    const __exports = {};
+    async function __wbg_init(module_or_path) {}
+    wasm_bindgen = Object.assign(__wbg_init, { initSync }, __exports);
+})();
+";
+
+    fn convention(source: &str) -> Convention {
+        glue(source.as_bytes()).unwrap().unwrap().convention
+    }
+
+    #[test]
+    fn takes_module_or_path() {
+        let glue = glue(MODULE_OR_PATH.as_bytes()).unwrap().unwrap();
+        assert_eq!(glue.convention, Convention::ModuleOrPath);
+        assert_eq!(glue.source, MODULE_OR_PATH.as_bytes());
+        assert!(glue.imports.is_empty());
+
+        // Glue with threads takes the memory second.
+        let threads = MODULE_OR_PATH.replace("(module_or_path)", "(module_or_path, memory)");
+        assert_eq!(convention(&threads), Convention::ModuleOrPath);
+    }
+
+    #[test]
+    fn takes_input() {
+        assert_eq!(convention(INPUT), Convention::Input);
+
+        let older = INPUT.replace("async function __wbg_init(", "async function init(");
+        assert_eq!(convention(&older), Convention::Input);
+    }
+
+    #[test]
+    fn exports_no_modules_global() {
+        let glue = glue(NO_MODULES.as_bytes()).unwrap().unwrap();
+        assert_eq!(glue.convention, Convention::ModuleOrPath);
+        assert!(glue.source.ends_with(b"\nexport default wasm_bindgen;\n"));
+    }
+
+    #[test]
+    fn rejects_glue_loading_its_module() {
+        let bundler = "\
+import * as wasm from \"./app_bg.wasm\";
+import { __wbg_set_wasm } from \"./app_bg.js\";
+__wbg_set_wasm(wasm);
+export * from \"./app_bg.js\";
+";
+        let nodejs = "\
+let imports = {};
+imports['__wbindgen_placeholder__'] = module.exports;
+const { TextDecoder } = require(`util`);
+";
+        let deno = "\
+const wasmCode = await Deno.readFile(wasm_url);
+export function __wbg_alert() {}
+";
+
+        for (source, target) in [(bundler, "bundler"), (nodejs, "nodejs"), (deno, "deno")] {
+            let err = glue(source.as_bytes()).err().unwrap().to_string();
+            assert!(err.contains(&format!("`{target}` target")), "{err}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_initialization() {
+        let renamed = MODULE_OR_PATH.replace("(module_or_path)", "(source)");
+        assert!(glue(renamed.as_bytes()).is_err());
+
+        let without_init = "export function __wbg_alert() {}\nexport default {};\n";
+        assert!(glue(without_init.as_bytes()).is_err());
+
+        let without_export = INPUT.replace("export default __wbg_init;", "");
+        assert!(glue(without_export.as_bytes()).is_err());
+
+        assert!(glue(b"export default async function() {}")
+            .unwrap()
+            .is_none());
+    }
+
+    /// Glue of a module that imports from WASI besides wasm-bindgen.
+    fn with_imports(imports: &str) -> String {
+        format!("{imports}\n{MODULE_OR_PATH}")
+    }
+
+    #[test]
+    fn rewrites_single_line_imports() {
+        let source = with_imports(
+            "import * as __wbg_star0 from 'wasi_snapshot_preview1';\n\
+             import { fd_write, proc_exit as exit } from \"wasi_snapshot_preview1\";\n\
+             import * as env from 'env';",
+        );
+
+        let glue = glue(source.as_bytes()).unwrap().unwrap();
+        let text = core::str::from_utf8(&glue.source).unwrap();
+        assert_eq!(glue.imports, ["wasi_snapshot_preview1", "env"]);
+
+        assert!(!text.contains("import * as") && !text.contains("import {"));
+        assert!(text.starts_with("let __wbg_star0;\nlet fd_write, exit;\nlet env;\n"));
+        assert!(text.contains(&format!("export function {IMPORT_HOOK}(namespaces) {{")));
+        assert!(text.contains("  __wbg_star0 = namespaces[\"wasi_snapshot_preview1\"];\n"));
+        assert!(text.contains("  exit = namespaces[\"wasi_snapshot_preview1\"][\"proc_exit\"];\n"));
+        assert!(text.contains("  env = namespaces[\"env\"];\n"));
+    }
+
+    #[test]
+    fn rejects_multi_line_imports() {
+        for imports in [
+            "import {\n    fd_write,\n    proc_exit,\n} from 'wasi_snapshot_preview1';",
+            "import { fd_write }\n    from \"wasi_snapshot_preview1\";",
+            "import *\n  as __wbg_star0 from 'wasi_snapshot_preview1';",
+        ] {
+            let err = glue(with_imports(imports).as_bytes()).err().unwrap();
+            assert!(err.to_string().contains("over several lines"), "{err}");
+        }
+    }
+
+    #[test]
+    fn keeps_relative_imports() {
+        for imports in [
+            "import { helper } from './snippets/app/helper.js';",
+            "import * as util from \"../util.js\";",
+            "import { Worker } from 'https://example.org/worker.js';",
+            "import {\n    helper,\n} from './snippets/app/helper.js';",
+        ] {
+            let source = with_imports(imports);
+            let glue = glue(source.as_bytes()).unwrap().unwrap();
+            assert_eq!(glue.source, source.as_bytes());
+            assert!(glue.imports.is_empty());
+        }
+    }
+
+    #[test]
+    fn fills_in_the_convention() {
+        let loader = format!("let convention = '{CONVENTION_PLACEHOLDER}';").into_bytes();
+        assert_eq!(
+            Convention::Input.apply(&loader),
+            b"let convention = 'input';"
+        );
+        assert_eq!(
+            Convention::ModuleOrPath.apply(&loader),
+            b"let convention = 'module_or_path';"
+        );
+    }
+}
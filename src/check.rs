@@ -0,0 +1,501 @@
+//! Verifies the polyglot invariants of a packed file.
+//!
+//! Every target relies on each of its formats finding its own structure in the same bytes. Short
+//! of opening the file in each reader, we confirm that structure here: the module parses with
+//! stage 0 in front, an HTML tokenizer reaches the stage 0 script before it would display any of
+//! the binary data, and a tar reader accepts the `html+tar` archive.
+use std::io::Write;
+
+use crate::{
+    inspect::{self, Document},
+    Target,
+};
+
+/// The entry of an `html+tar` archive that holds the module.
+const TAR_BOOT: &str = "boot/wah-init.wasm";
+/// The range of the checksum field within a tar header.
+const TAR_CHECKSUM: core::ops::Range<usize> = 148..156;
+
+/// Elements whose contents the tokenizer reads as text, up to their end tag.
+const RCDATA_ELEMENTS: &[&[u8]] = &[b"title", b"textarea"];
+const RAWTEXT_ELEMENTS: &[&[u8]] = &[
+    b"style",
+    b"xmp",
+    b"iframe",
+    b"noembed",
+    b"noframes",
+    b"noscript",
+];
+
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+pub struct Finding {
+    /// The invariant, phrased as a statement that holds.
+    pub invariant: &'static str,
+    /// Why the invariant does not hold for the file, if it does not.
+    pub failure: Option<String>,
+}
+
+/// Check all the invariants that apply to the target of a file.
+pub fn check(data: &[u8]) -> Report {
+    let mut findings = vec![];
+
+    let document = match Document::new(data) {
+        Ok(document) => document,
+        Err(err) => {
+            findings.push(Finding {
+                invariant: "the module parses as WebAssembly",
+                failure: Some(err.to_string()),
+            });
+
+            return Report { findings };
+        }
+    };
+
+    findings.push(Finding {
+        invariant: "the module parses as WebAssembly",
+        failure: None,
+    });
+
+    let first = document.sections.first().and_then(|s| s.name.as_deref());
+    findings.push(Finding {
+        invariant: "stage 0 is the first section",
+        failure: match first {
+            Some("wah_polyglot_stage0") => None,
+            Some(name) => Some(format!("the first section is `{name}`")),
+            None => Some("the first section is not a custom section".into()),
+        },
+    });
+
+    let stage2 = document
+        .sections
+        .iter()
+        .filter(|section| section.name.as_deref() == Some("wah_polyglot_stage2"))
+        .count();
+
+    // The stage 2 loader of an encrypted document is sealed with the rest of the module.
+    if !document.is_encrypted() {
+        findings.push(Finding {
+            invariant: "there is exactly one stage 2 loader",
+            failure: match stage2 {
+                1 => None,
+                n => Some(format!("found {n} `wah_polyglot_stage2` sections")),
+            },
+        });
+    }
+
+    // The EPUB's page is a resource of its own, the file itself is no page.
+    if document.target != Target::Epub {
+        findings.push(Finding {
+            invariant: "HTML reaches the stage 0 script before any binary data",
+            failure: tokenize_to_script(data).err(),
+        });
+    }
+
+    if document.target == Target::HtmlPlusTar {
+        let listing = list_tar(data);
+
+        findings.push(Finding {
+            invariant: "all tar header checksums are correct",
+            failure: listing.as_ref().err().cloned(),
+        });
+
+        findings.push(Finding {
+            invariant: "tar lists the entries, with the module",
+            failure: match listing {
+                Ok(names) if names.iter().any(|name| name == TAR_BOOT) => None,
+                Ok(_) => Some(format!("no entry `{TAR_BOOT}`")),
+                Err(_) => Some("the archive is unreadable".into()),
+            },
+        });
+    }
+
+    Report { findings }
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.findings
+            .iter()
+            .all(|finding| finding.failure.is_none())
+    }
+
+    pub fn report(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        for finding in &self.findings {
+            match &finding.failure {
+                None => writeln!(out, "ok      {}", finding.invariant)?,
+                Some(why) => writeln!(out, "FAILED  {}: {why}", finding.invariant)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the HTML5 tokenizer up to the first script start tag.
+///
+/// Before the first markup, the header of a module or archive is acceptable text: browsers render
+/// it but stage 0 hides the page anyway. After that, control characters in the text mean that the
+/// tokenizer has run into binary data, which no comment, attribute or template covers.
+fn tokenize_to_script(data: &[u8]) -> Result<usize, String> {
+    let mut markup = false;
+    // Contents of templates are inert, these never show.
+    let mut templates = 0usize;
+    let mut i = 0;
+
+    while i < data.len() {
+        let byte = data[i];
+
+        if byte != b'<' {
+            if markup && templates == 0 && is_binary(byte) {
+                return Err(format!("binary data at offset {i} is text of the page"));
+            }
+
+            i += 1;
+            continue;
+        }
+
+        let rest = &data[i + 1..];
+        let Some(&next) = rest.first() else {
+            break;
+        };
+
+        if next.is_ascii_alphabetic() {
+            let tag = scan_tag(data, i + 1).ok_or("the file ends within a tag")?;
+            markup = true;
+
+            if tag.name == b"script" {
+                return Ok(i);
+            } else if tag.name == b"plaintext" {
+                return Err("a `plaintext` element makes the rest of the file text".into());
+            } else if tag.name == b"template" {
+                templates += 1;
+            }
+
+            i = tag.end;
+            let raw = RCDATA_ELEMENTS
+                .iter()
+                .chain(RAWTEXT_ELEMENTS)
+                .any(|name| *name == tag.name);
+
+            if raw {
+                let close = find_end_tag(data, i, &tag.name)
+                    .ok_or_else(|| format!("the `{}` element is never closed", tag.display()))?;
+
+                if let Some(at) = data[i..close].iter().position(|&b| is_binary(b)) {
+                    return Err(format!(
+                        "binary data at offset {} is text of the page",
+                        i + at
+                    ));
+                }
+
+                i = close;
+            }
+        } else if next == b'/' {
+            match rest.get(1) {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let tag = scan_tag(data, i + 2).ok_or("the file ends within a tag")?;
+                    if tag.name == b"template" {
+                        templates = templates.saturating_sub(1);
+                    }
+
+                    i = tag.end;
+                }
+                Some(b'>') => i += 3,
+                _ => i = bogus_comment(data, i + 2)?,
+            }
+            markup = true;
+        } else if next == b'!' {
+            i = declaration(data, i + 2)?;
+            markup = true;
+        } else if next == b'?' {
+            i = bogus_comment(data, i + 1)?;
+            markup = true;
+        } else {
+            // A lone `<` is text.
+            i += 1;
+        }
+    }
+
+    Err("the file ends before any script".into())
+}
+
+struct Tag {
+    /// The lowercase tag name.
+    name: Vec<u8>,
+    /// The offset after the tag.
+    end: usize,
+}
+
+impl Tag {
+    fn display(&self) -> String {
+        String::from_utf8_lossy(&self.name).into_owned()
+    }
+}
+
+/// Scan a tag from its name through its attributes, as the tokenizer's tag states do.
+fn scan_tag(data: &[u8], start: usize) -> Option<Tag> {
+    let name_len = data[start..]
+        .iter()
+        .position(|&b| is_space(b) || b == b'/' || b == b'>')?;
+    let name = data[start..start + name_len].to_ascii_lowercase();
+    let mut i = start + name_len;
+
+    loop {
+        // Before an attribute name, or after an attribute.
+        while is_space(*data.get(i)?) || data[i] == b'/' {
+            i += 1;
+        }
+
+        if data[i] == b'>' {
+            return Some(Tag { name, end: i + 1 });
+        }
+
+        // The attribute name, whose first character may be an `=`.
+        i += 1;
+        while !matches!(
+            *data.get(i)?,
+            b'\t' | b'\n' | b'\x0c' | b' ' | b'/' | b'>' | b'='
+        ) {
+            i += 1;
+        }
+
+        while is_space(*data.get(i)?) {
+            i += 1;
+        }
+
+        if data[i] != b'=' {
+            continue;
+        }
+
+        i += 1;
+        while is_space(*data.get(i)?) {
+            i += 1;
+        }
+
+        match data[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 2 + data[i + 1..].iter().position(|&b| b == quote)?;
+            }
+            b'>' => {}
+            _ => {
+                while !is_space(*data.get(i)?) && data[i] != b'>' {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+/// The start of the end tag matching an element of raw text, searching from an offset.
+fn find_end_tag(data: &[u8], from: usize, name: &[u8]) -> Option<usize> {
+    let mut i = from;
+
+    loop {
+        i += inspect::find(&data[i..], b"</")?;
+        let after = i + 2 + name.len();
+
+        let is_match = data
+            .get(i + 2..after)
+            .is_some_and(|candidate| candidate.eq_ignore_ascii_case(name))
+            && data
+                .get(after)
+                .is_some_and(|&b| is_space(b) || b == b'/' || b == b'>');
+
+        if is_match {
+            return Some(i);
+        }
+
+        i += 2;
+    }
+}
+
+/// Skip a comment, doctype or other declaration after its `<!`.
+fn declaration(data: &[u8], start: usize) -> Result<usize, String> {
+    let rest = &data[start..];
+
+    if let Some(comment) = rest.strip_prefix(b"--") {
+        // Abruptly closed empty comments.
+        if comment.starts_with(b">") {
+            return Ok(start + 3);
+        } else if comment.starts_with(b"->") {
+            return Ok(start + 4);
+        }
+
+        let body = start + 2;
+        let mut i = body;
+        loop {
+            let at = inspect::find(&data[i..], b"--")
+                .ok_or_else(|| format!("the comment at offset {} is never closed", start - 2))?;
+            i += at + 2;
+
+            match data.get(i) {
+                Some(b'>') => return Ok(i + 1),
+                Some(b'!') if data.get(i + 1) == Some(&b'>') => return Ok(i + 2),
+                _ => i -= 1,
+            }
+        }
+    }
+
+    bogus_comment(data, start)
+}
+
+/// Skip a doctype or bogus comment, which both end at the next `>`.
+fn bogus_comment(data: &[u8], start: usize) -> Result<usize, String> {
+    match data[start..].iter().position(|&b| b == b'>') {
+        Some(end) => Ok(start + end + 1),
+        None => Err(format!("the declaration at offset {start} is never closed")),
+    }
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+/// Control characters that no text document contains.
+///
+/// Tree construction drops NUL characters from the text, so these do not show.
+fn is_binary(byte: u8) -> bool {
+    (byte != 0 && byte < 0x20 && !is_space(byte)) || byte == 0x7f
+}
+
+/// List the names of a tar archive, failing on the first header that a tar reader rejects.
+fn list_tar(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    let mut offset = 0;
+
+    loop {
+        let header = data
+            .get(offset..offset + 512)
+            .ok_or("the archive ends without an end-of-archive block")?;
+
+        if header.iter().all(|&b| b == 0) {
+            return Ok(names);
+        }
+
+        let stored = inspect::parse_octal(&header[TAR_CHECKSUM])
+            .ok_or_else(|| format!("the header at offset {offset} has no checksum"))?;
+
+        // Readers accept the sum of either unsigned or signed bytes.
+        let blank = TAR_CHECKSUM.len() * usize::from(b' ');
+        let fields = header[..TAR_CHECKSUM.start]
+            .iter()
+            .chain(&header[TAR_CHECKSUM.end..]);
+        let unsigned: usize = fields.clone().map(|&b| usize::from(b)).sum::<usize>() + blank;
+        let signed: isize = fields.map(|&b| isize::from(b as i8)).sum::<isize>() + blank as isize;
+
+        if stored != unsigned && stored as isize != signed {
+            return Err(format!(
+                "the header at offset {offset} has checksum {stored:o}, not {unsigned:o}"
+            ));
+        }
+
+        let size = inspect::parse_octal(&header[124..136])
+            .ok_or_else(|| format!("the header at offset {offset} has an invalid size"))?;
+        let end = (offset + 512)
+            .checked_add(size)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| format!("the entry at offset {offset} extends past the end of file"))?;
+
+        names.push(String::from_utf8_lossy(inspect::until_nul(&header[..100])).into_owned());
+        offset = end.next_multiple_of(512);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tar header of a regular file, with its checksum.
+    fn tar_header(name: &str, size: &str) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..124 + size.len()].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+
+        header[TAR_CHECKSUM].fill(b' ');
+        let sum: usize = header.iter().map(|&b| usize::from(b)).sum();
+        header[TAR_CHECKSUM.start..TAR_CHECKSUM.start + 7]
+            .copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        header
+    }
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = vec![];
+        for (name, data) in entries {
+            tar.extend(tar_header(name, &format!("{:011o}", data.len())));
+            tar.extend_from_slice(data);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+
+        tar.extend([0; 1024]);
+        tar
+    }
+
+    #[test]
+    fn tokenizer_reaches_the_script() {
+        let passing: &[&[u8]] = &[
+            b"<!DOCTYPE html><title>Page</title><script>",
+            // The header of the module is text before any markup.
+            b"\0asm\x01\0\0\0\x05<html><script>",
+            b"<!-- \x01\x02 --><p data-x='\x03'>Text<script>",
+            b"<template>\x01\x02</template><SCRIPT type=module>",
+            b"<style>p { }</style><textarea></title></textarea><script>",
+        ];
+
+        for html in passing {
+            let at = tokenize_to_script(html).unwrap();
+            assert!(html[at..].to_ascii_lowercase().starts_with(b"<script"));
+        }
+    }
+
+    #[test]
+    fn tokenizer_finds_binary_text() {
+        let failing: &[&[u8]] = &[
+            b"<html><title>\x01\x02</title><script>",
+            b"<html>\x7f<script>",
+            b"<p><plaintext><script>",
+            b"<title>Never closed<script>",
+            b"<!-- never closed <script>",
+            b"<html>",
+        ];
+
+        for html in failing {
+            assert!(
+                tokenize_to_script(html).is_err(),
+                "{:?}",
+                html.escape_ascii()
+            );
+        }
+    }
+
+    #[test]
+    fn tar_lists_entries() {
+        let data = tar(&[
+            ("boot/wah-init.wasm", b"\0asm"),
+            ("index.html", &[b'x'; 600]),
+        ]);
+        assert_eq!(
+            list_tar(&data).unwrap(),
+            ["boot/wah-init.wasm", "index.html"]
+        );
+    }
+
+    #[test]
+    fn tar_rejects_damaged_archives() {
+        let mut bad_checksum = tar(&[("boot/wah-init.wasm", b"\0asm")]);
+        bad_checksum[0] = b'B';
+        assert!(list_tar(&bad_checksum).unwrap_err().contains("checksum"));
+
+        let mut truncated = tar(&[("boot/wah-init.wasm", &[0; 1024])]);
+        truncated.truncate(1024);
+        assert!(list_tar(&truncated).is_err());
+
+        let mut huge = tar_header("boot/wah-init.wasm", "777777777777");
+        huge.extend([0; 1024]);
+        assert!(list_tar(&huge).unwrap_err().contains("past the end"));
+    }
+}
@@ -1,3 +1,9 @@
+// The tar structure is only used by `html+tar`, the conversion to XHTML only by `epub`.
+#![cfg_attr(
+    not(all(feature = "target-html+tar", feature = "target-epub")),
+    allow(dead_code)
+)]
+
 use core::{error::Error, ops};
 use std::borrow::Cow;
 
@@ -22,6 +28,15 @@ pub struct SourceCharacter {
     pub column: usize,
 }
 
+/// The index HTML as the chapter of an EPUB.
+pub struct XhtmlChapter {
+    pub xhtml: String,
+    /// The contents of `<title>`, if any.
+    pub title: Option<String>,
+    /// The `lang` of the `<html>` tag, if any.
+    pub language: Option<String>,
+}
+
 pub struct SourceDocument<'text> {
     text: Cow<'text, str>,
     by_line: Vec<usize>,
@@ -186,6 +201,201 @@ fn parse_tar_tags(source: &mut SourceDocument) -> Result<Structure, Box<dyn Erro
     })
 }
 
+fn parse_xhtml_chapter(
+    source: &SourceDocument,
+    loader: &str,
+) -> Result<XhtmlChapter, Box<dyn Error>> {
+    let mut dom = Dom::parse(&source.text)?;
+
+    // The scripts of the page are meant for the loaded module, not for the static chapter that
+    // readers without scripting display.
+    remove_elements(&mut dom.children, "script");
+
+    let head = find_element_mut(&mut dom, |node| {
+        node.element()
+            .filter(|el| el.name.to_lowercase() == "head")
+            .is_some()
+    })
+    .and_then(|el| match el {
+        lithtml::Node::Element(el) => Some(el),
+        _ => None,
+    })
+    .ok_or("Missing Node to insert the EPUB loader, searched for `<head>` tag")?;
+
+    let synth_script = lithtml::Element {
+        name: "script".into(),
+        variant: lithtml::ElementVariant::Normal,
+        attributes: [(Cow::Borrowed("src"), Some(Cow::Borrowed(loader)))]
+            .into_iter()
+            .collect(),
+        classes: vec![],
+        children: vec![],
+        source_span: head.source_span.clone(),
+    };
+
+    head.children.push(lithtml::Node::Element(synth_script));
+
+    let html = find_element(&dom, |node| {
+        node.element().filter(|el| el.name.to_lowercase() == "html")
+    })
+    .ok_or("Missing Node to convert to XHTML, searched for `<html>` tag")?;
+
+    let title = find_element(&dom, |node| {
+        node.element()
+            .filter(|el| el.name.to_lowercase() == "title")
+    })
+    .map(|el| {
+        let text: String = el.children.iter().filter_map(Node::text).collect();
+        decode_entities(text.trim())
+    });
+
+    let language = html
+        .attributes
+        .get("lang")
+        .and_then(Option::as_deref)
+        .map(decode_entities);
+
+    let mut xhtml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n");
+    write_xhtml(html, &mut xhtml);
+    xhtml.push('\n');
+
+    Ok(XhtmlChapter {
+        xhtml,
+        title,
+        language,
+    })
+}
+
+fn remove_elements(children: &mut Vec<Node>, name: &str) {
+    children.retain(|node| {
+        node.element()
+            .is_none_or(|el| el.name.to_lowercase() != name)
+    });
+
+    for node in children {
+        if let lithtml::Node::Element(el) = node {
+            remove_elements(&mut el.children, name);
+        }
+    }
+}
+
+/// Serialize an element as well-formed XML, in the XHTML namespace.
+fn write_xhtml(el: &lithtml::Element, out: &mut String) {
+    const XMLNS: &[(&str, &str)] = &[
+        ("xmlns", "http://www.w3.org/1999/xhtml"),
+        ("xmlns:epub", "http://www.idpf.org/2007/ops"),
+    ];
+
+    let name = el.name.to_lowercase();
+    out.push('<');
+    out.push_str(&name);
+
+    if name == "html" {
+        for (attr, value) in XMLNS {
+            out.push_str(&format!(" {attr}=\"{value}\""));
+        }
+    }
+
+    if !el.classes.is_empty() {
+        out.push_str(&format!(" class=\"{}\"", xml_escape(&el.classes.join(" "))));
+    }
+
+    // Sorted, so that the chapter does not depend on the order of a hash map.
+    let mut attributes: Vec<_> = el
+        .attributes
+        .iter()
+        .map(|(attr, value)| (attr.to_lowercase(), value))
+        .filter(|(attr, _)| !XMLNS.iter().any(|(xmlns, _)| xmlns == attr))
+        .collect();
+    attributes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (attr, value) in attributes {
+        // Boolean attributes are written out in XML.
+        let value = value.as_deref().unwrap_or(&attr);
+        out.push_str(&format!(" {attr}=\"{}\"", xml_escape(value)));
+    }
+
+    if let lithtml::ElementVariant::Void = el.variant {
+        out.push_str(" />");
+        return;
+    }
+
+    out.push('>');
+
+    for child in &el.children {
+        match child {
+            lithtml::Node::Element(el) => write_xhtml(el, out),
+            lithtml::Node::Text(text) => out.push_str(&xml_escape(text)),
+            // Comments may contain `--`, which XML does not allow.
+            lithtml::Node::Comment(_) => {}
+        }
+    }
+
+    out.push_str(&format!("</{name}>"));
+}
+
+/// Escape HTML source text for XML, which only knows a few named character references.
+fn xml_escape(text: &str) -> String {
+    decode_entities(text)
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Replace the character references of the source, as far as we know them.
+///
+/// Unknown references are kept verbatim, and are escaped as text when written as XML.
+fn decode_entities(text: &str) -> String {
+    const NAMED: &[(&str, char)] = &[
+        ("amp", '&'),
+        ("lt", '<'),
+        ("gt", '>'),
+        ("quot", '"'),
+        ("apos", '\''),
+        ("nbsp", '\u{a0}'),
+        ("copy", '\u{a9}'),
+        ("ndash", '\u{2013}'),
+        ("mdash", '\u{2014}'),
+        ("hellip", '\u{2026}'),
+    ];
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let reference = rest[1..].find(';').map(|end| &rest[1..1 + end]);
+        let decoded = reference.and_then(|reference| match reference.strip_prefix('#') {
+            Some(number) => match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => number.parse().ok(),
+            }
+            .and_then(char::from_u32),
+            None => NAMED
+                .iter()
+                .find(|(name, _)| *name == reference)
+                .map(|&(_, ch)| ch),
+        });
+
+        match (reference, decoded) {
+            (Some(reference), Some(ch)) => {
+                out.push(ch);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
 fn find_element<'a, T>(dom: &'a Dom, mut with: impl FnMut(&'a Node) -> Option<T>) -> Option<T> {
     let mut stack: Vec<_> = dom.children.iter().collect();
 
@@ -289,6 +499,11 @@ impl<'text> SourceDocument<'text> {
     pub fn prepare_tar_structure(&mut self) -> Result<Structure, Box<dyn Error>> {
         parse_tar_tags(self)
     }
+
+    /// Convert the document to an XHTML chapter that loads the given script.
+    pub fn prepare_xhtml_chapter(&self, loader: &str) -> Result<XhtmlChapter, Box<dyn Error>> {
+        parse_xhtml_chapter(self, loader)
+    }
 }
 
 impl<'text> ops::Index<ops::Range<usize>> for SourceDocument<'text> {
@@ -0,0 +1,154 @@
+//! Recognizes Emscripten glue, for the `builtin:emscripten` stage 2 loader.
+//!
+//! Emscripten emits the module along with a JS glue that fetches it, and preloads files with
+//! `Module.preRun`. Our loader takes the glue from the `wah_polyglot_emscripten` section, hands
+//! it the module of the document through `Module.instantiateWasm` and writes the data archive
+//! into its `FS`. Glue given as the stage 2 loader moves into that section by itself.
+//!
+//! Emscripten only exports `FS` on the `Module` object when asked to, with
+//! `-sEXPORTED_RUNTIME_METHODS=FS`. A document with a data archive requires glue built so.
+//!
+//! The glue is either a script that picks up a global `Module`, or with `-sMODULARIZE` a factory
+//! taking it. With `-sEXPORT_ES6` that factory is the default export of a module, otherwise we
+//! add that export.
+use std::borrow::Cow;
+
+use crate::inspect::find;
+
+/// The section that `builtin:emscripten` takes the glue from.
+pub const SECTION: &str = "wah_polyglot_emscripten";
+/// Where the loader takes the kind of the glue.
+pub const KIND_PLACEHOLDER: &str = "__REPLACE_THIS_WITH_EMSCRIPTEN_KIND__";
+/// Where the loader takes the name of the data archive section.
+pub const DATA_SECTION_PLACEHOLDER: &str = "__REPLACE_THIS_WITH_EMSCRIPTEN_DATA_SECTION__";
+
+/// How the loader runs the glue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// An ES module with a default export that takes the `Module` object.
+    Module,
+    /// A classic script that uses a global `Module` object.
+    Script,
+}
+
+/// Glue as it is packed.
+pub struct Glue<'src> {
+    pub kind: Kind,
+    pub source: Cow<'src, [u8]>,
+    /// Whether the glue exports `FS`, through which the loader writes the data archive.
+    pub exports_fs: bool,
+}
+
+impl Kind {
+    /// Fill in the kind and the section of the data archive for the loader.
+    pub fn apply(self, loader: &[u8], data_section: &str) -> Vec<u8> {
+        let name = match self {
+            Kind::Module => "module",
+            Kind::Script => "script",
+        };
+
+        String::from_utf8_lossy(loader)
+            .replace(KIND_PLACEHOLDER, name)
+            // Escaped like a Rust literal, which is valid within the quotes in JS as well.
+            .replace(
+                DATA_SECTION_PLACEHOLDER,
+                &data_section.escape_default().to_string(),
+            )
+            .into_bytes()
+    }
+}
+
+/// Recognize Emscripten glue, returning `None` for other code.
+pub fn glue(source: &[u8]) -> Option<Glue<'_>> {
+    let mentions = |what: &str| find(source, what.as_bytes()).is_some();
+
+    if !mentions("emscripten") || !mentions("preRun") || !mentions("instantiateWasm") {
+        return None;
+    }
+
+    // Our loader, which reads the glue from its section.
+    if mentions(SECTION) {
+        return None;
+    }
+
+    // As `Module["FS"] = FS`, or minified without the quotes.
+    let exports_fs = ["[\"FS\"]", "['FS']", ".FS="]
+        .iter()
+        .any(|export| mentions(export));
+
+    if mentions("export default") {
+        return Some(Glue {
+            kind: Kind::Module,
+            source: Cow::Borrowed(source),
+            exports_fs,
+        });
+    }
+
+    // A modularized script exports its factory for CommonJS, for ES modules instead.
+    let text = String::from_utf8_lossy(source);
+    let factory = text.split("module.exports = ").skip(1).find_map(|rest| {
+        let name = rest.split(';').next()?.trim();
+        let is_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+
+        is_name.then_some(name)
+    });
+
+    Some(match factory {
+        Some(factory) => {
+            let mut module = source.to_vec();
+            module.extend_from_slice(format!("\nexport default {factory};\n").as_bytes());
+
+            Glue {
+                kind: Kind::Module,
+                source: Cow::Owned(module),
+                exports_fs,
+            }
+        }
+        None => Glue {
+            kind: Kind::Script,
+            source: Cow::Borrowed(source),
+            exports_fs,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLUE: &str = "\
+// emscripten glue
+async function Module(moduleArg = {}) {
+  var Module = moduleArg;
+  Module['instantiateWasm'];
+  Module.preRun;
+  Module[\"FS\"] = FS;
+}
+export default Module;
+";
+
+    #[test]
+    fn recognizes_the_exported_file_system() {
+        let glue = glue(GLUE.as_bytes()).unwrap();
+        assert_eq!(glue.kind, Kind::Module);
+        assert!(glue.exports_fs);
+
+        let without = GLUE.replace("Module[\"FS\"] = FS;", "");
+        assert!(!super::glue(without.as_bytes()).unwrap().exports_fs);
+    }
+
+    #[test]
+    fn fills_in_the_data_section() {
+        let loader = format!("'{KIND_PLACEHOLDER}' '{DATA_SECTION_PLACEHOLDER}'");
+        let apply = |name| String::from_utf8(Kind::Script.apply(loader.as_bytes(), name)).unwrap();
+
+        assert_eq!(
+            apply("wah_polyglot_stage2_data"),
+            "'script' 'wah_polyglot_stage2_data'"
+        );
+        assert_eq!(apply("it's"), "'script' 'it\\'s'");
+    }
+}
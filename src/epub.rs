@@ -0,0 +1,138 @@
+//! Packages a module as an EPUB publication.
+//!
+//! An EPUB is a zip archive of XHTML documents, described by a package document. The publication
+//! has a single chapter, the index HTML converted to XHTML, which e-readers without scripting
+//! display as it is. Where scripting is available, the chapter's loader fetches the module from
+//! its neighbouring resource and hands off to stage 1 like any other stage 0.
+//!
+//! The archive is reproducible in the same way as [`crate::archive::from_dir`].
+use core::error::Error;
+use std::io::{Cursor, Write};
+
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipWriter};
+
+/// The directory of the publication's resources within the archive.
+pub const ROOT: &str = "EPUB/";
+/// The name of the module within [`ROOT`].
+pub const MODULE: &str = "wah-init.wasm";
+/// The name of the stage 0 loader within [`ROOT`].
+pub const LOADER: &str = "wah-epub.js";
+
+const MIMETYPE: &str = "application/epub+zip";
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// The parts of the publication besides its fixed files.
+pub struct Publication<'data> {
+    /// The chapter, as an XHTML document that includes the [`LOADER`].
+    pub chapter: &'data str,
+    pub title: &'data str,
+    pub language: &'data str,
+    /// The packed module.
+    pub module: &'data [u8],
+    /// The data archive, as a resource of its own.
+    pub archive: Option<&'data [u8]>,
+}
+
+impl Publication<'_> {
+    /// Write the publication as an EPUB archive.
+    pub fn to_epub(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut crc = flate2::Crc::new();
+        crc.update(self.module);
+        let identifier = format!("urn:wasm-as-html:{:08x}", crc.sum());
+
+        let title = xml_escape(self.title);
+        let language = xml_escape(self.language);
+
+        let archive_item = match self.archive {
+            Some(_) => "\n    <item id=\"data\" href=\"data.zip\" media-type=\"application/zip\"/>",
+            None => "",
+        };
+
+        let package = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:language>{language}</dc:language>
+    <meta property="dcterms:modified">1980-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml" properties="scripted"/>
+    <item id="loader" href="{LOADER}" media-type="application/javascript"/>
+    <item id="module" href="{MODULE}" media-type="application/wasm"/>{archive_item}
+  </manifest>
+  <spine>
+    <itemref idref="chapter"/>
+  </spine>
+</package>
+"#
+        );
+
+        let nav = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>{title}</title></head>
+<body>
+  <nav epub:type="toc"><ol><li><a href="chapter.xhtml">{title}</a></li></ol></nav>
+</body>
+</html>
+"#
+        );
+
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(DateTime::default())
+            .unix_permissions(0o644);
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+
+        // Readers identify the publication by this first entry, uncompressed.
+        zip.start_file(
+            "mimetype",
+            options.compression_method(CompressionMethod::Stored),
+        )?;
+        zip.write_all(MIMETYPE.as_bytes())?;
+
+        let mut files: Vec<(&str, &[u8])> = vec![
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            ("package.opf", package.as_bytes()),
+            ("nav.xhtml", nav.as_bytes()),
+            ("chapter.xhtml", self.chapter.as_bytes()),
+            (LOADER, include_bytes!("stage0-epub.js")),
+            (MODULE, self.module),
+        ];
+
+        if let Some(archive) = self.archive {
+            files.push(("data.zip", archive));
+        }
+
+        for (name, data) in files {
+            let name = if name.starts_with("META-INF/") {
+                name.to_string()
+            } else {
+                format!("{ROOT}{name}")
+            };
+
+            zip.start_file(name, options)?;
+            zip.write_all(data)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
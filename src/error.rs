@@ -1,5 +1,4 @@
-use std::borrow::Cow;
-
+use std::{borrow::Cow, path::PathBuf};
 
 #[derive(Debug)]
 pub struct UnsupportedFeatureError {
@@ -12,10 +11,102 @@ impl core::fmt::Display for UnsupportedFeatureError {
         write!(
             f,
             "Using {} requires the feature `{}` which was not enabled during compilation",
-            self.what_to_use,
-            self.feature,
+            self.what_to_use, self.feature,
         )
     }
 }
 
 impl core::error::Error for UnsupportedFeatureError {}
+
+#[derive(Debug)]
+pub struct UnrecognizedDocumentError {
+    pub reason: Cow<'static, str>,
+}
+
+impl core::fmt::Display for UnrecognizedDocumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not a document produced by this packer: {}", self.reason)
+    }
+}
+
+impl core::error::Error for UnrecognizedDocumentError {}
+
+/// The input is not a well-formed or valid WebAssembly module.
+#[derive(Debug)]
+pub struct InvalidModuleError {
+    /// The file the module was read from, if any.
+    pub file: Option<PathBuf>,
+    pub message: String,
+    /// The byte offset in the module at which the error was detected.
+    pub offset: usize,
+}
+
+impl From<wasmparser::BinaryReaderError> for InvalidModuleError {
+    fn from(err: wasmparser::BinaryReaderError) -> Self {
+        InvalidModuleError {
+            file: None,
+            message: err.message().to_string(),
+            offset: err.offset(),
+        }
+    }
+}
+
+impl core::fmt::Display for InvalidModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+
+        write!(
+            f,
+            "Invalid WebAssembly module at offset {:#x}: {}",
+            self.offset, self.message,
+        )
+    }
+}
+
+impl core::error::Error for InvalidModuleError {}
+
+/// A manifest, or a value in it, is not usable.
+#[derive(Debug)]
+pub struct ManifestError {
+    /// The manifest key, or file, that the error refers to.
+    pub key: String,
+    pub message: String,
+}
+
+impl core::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl core::error::Error for ManifestError {}
+
+/// The data archive is not a zip file that the packer can place into the document.
+#[derive(Debug)]
+pub struct InvalidArchiveError {
+    pub reason: Cow<'static, str>,
+}
+
+impl core::fmt::Display for InvalidArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid zip archive: {}", self.reason)
+    }
+}
+
+impl core::error::Error for InvalidArchiveError {}
+
+/// The PDF can not be embedded such that readers still find its objects.
+#[derive(Debug)]
+pub struct InvalidPdfError {
+    pub reason: Cow<'static, str>,
+}
+
+impl core::fmt::Display for InvalidPdfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported PDF: {}", self.reason)
+    }
+}
+
+impl core::error::Error for InvalidPdfError {}
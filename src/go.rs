@@ -0,0 +1,139 @@
+//! Recognizes the `wasm_exec.js` of Go and TinyGo, for the `builtin:go` stage 2 loader.
+//!
+//! Modules of Go's `js/wasm` target import the runtime from the `go` or `gojs` namespace, which
+//! the `Go` class of `wasm_exec.js` provides as its `importObject`. That file has to come from
+//! the toolchain that built the module, as the imports change between versions, so it is packed
+//! as the `wah_polyglot_go` section. Given as the stage 2 loader it moves there by itself.
+//!
+//! Arguments and environment variables are taken from `input.args` and `input.env` of the
+//! `wah_wasi_config` section, the configuration that `wasi-loader/interpret` defines, and filled
+//! into our loader when packing. The arguments follow the program name `js`, which is what
+//! `wasm_exec.js` passes by default.
+use core::error::Error;
+
+use serde::Deserialize;
+
+use crate::inspect::find;
+
+/// The section that `builtin:go` takes `wasm_exec.js` from.
+pub const SECTION: &str = "wah_polyglot_go";
+/// The section of the declarative configuration.
+pub const CONFIG_SECTION: &str = "wah_wasi_config";
+/// Where the loader takes the arguments of the program.
+pub const ARGS_PLACEHOLDER: &str = "/* __REPLACE_THIS_WITH_GO_ARGS__ */";
+/// Where the loader takes the environment variables of the program.
+pub const ENV_PLACEHOLDER: &str = "/* __REPLACE_THIS_WITH_GO_ENV__ */";
+
+/// The part of the configuration that a Go program takes.
+///
+/// Mirrors `wasi_loader::config`, other keys are left to the WASI loader.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Config {
+    input: Input,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Input {
+    args: Vec<String>,
+    /// Variables as `KEY=value`.
+    env: Vec<String>,
+}
+
+/// Recognize `wasm_exec.js`, which defines the `Go` class.
+pub fn is_wasm_exec(source: &[u8]) -> bool {
+    let mentions = |what: &str| find(source, what.as_bytes()).is_some();
+
+    // Our loader reads it from its section instead.
+    mentions("Go = class") && mentions("importObject") && !mentions(SECTION)
+}
+
+/// Fill in arguments and environment from the configuration, if there is one.
+pub fn apply(loader: &[u8], config: Option<&[u8]>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let config = match config {
+        None => Config::default(),
+        Some(config) => {
+            let text = core::str::from_utf8(config)
+                .map_err(|err| format!("the `{CONFIG_SECTION}` section is not UTF-8: {err}"))?;
+            toml::from_str(text)
+                .map_err(|err| format!("the `{CONFIG_SECTION}` section is invalid: {err}"))?
+        }
+    };
+
+    // Escaped like Rust literals, which are valid in JS as well.
+    let literals = |strings: &[String]| {
+        strings
+            .iter()
+            .map(|string| format!("\"{}\"", string.escape_default()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    Ok(String::from_utf8_lossy(loader)
+        .replace(ARGS_PLACEHOLDER, &literals(&config.input.args))
+        .replace(ENV_PLACEHOLDER, &literals(&config.input.env))
+        .into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage2::{Builtin, Flavor};
+
+    const WASM_EXEC: &str = "\"use strict\";\n\
+        (() => {\n\
+          globalThis.Go = class {\n\
+            constructor() {\n\
+              this.argv = [\"js\"];\n\
+              this.importObject = { gojs: {} };\n\
+            }\n\
+          };\n\
+        })();\n";
+
+    #[test]
+    fn recognizes_wasm_exec() {
+        assert!(is_wasm_exec(WASM_EXEC.as_bytes()));
+
+        // Our loader uses the class as well, but reads it from its section.
+        let loader = Builtin::Go.source().unwrap();
+        assert!(!is_wasm_exec(loader));
+        assert!(!is_wasm_exec(b"export default async function(wasm) {}"));
+    }
+
+    #[test]
+    fn fills_in_escaped_literals() {
+        let config = br#"
+            [input]
+            args = ["app", "--name", "s\u00e4 \"q\" 'x'"]
+            env = ["HOME=/home/go", "PATH=C:\\bin"]
+        "#;
+
+        let loader = apply(Builtin::Go.source().unwrap(), Some(config)).unwrap();
+        let loader = String::from_utf8(loader).unwrap();
+
+        assert!(loader.contains(r#"let args = ["app", "--name", "s\u{e4} \"q\" \'x\'"];"#));
+        assert!(loader.contains(r#"let env = ["HOME=/home/go", "PATH=C:\\bin"];"#));
+        assert!(!loader.contains(ARGS_PLACEHOLDER));
+        assert!(!loader.contains(ENV_PLACEHOLDER));
+    }
+
+    #[test]
+    fn fills_in_nothing_without_configuration() {
+        let loader = apply(Builtin::Go.source().unwrap(), None).unwrap();
+        let loader = String::from_utf8(loader).unwrap();
+
+        assert!(loader.contains("let args = [];"));
+        assert!(loader.contains("let env = [];"));
+        assert!(apply(WASM_EXEC.as_bytes(), Some(b"[input]\nargs = 1")).is_err());
+    }
+
+    #[test]
+    fn builtin_loader_is_for_go() {
+        let loader = Builtin::Go.source().unwrap();
+
+        assert_eq!(Flavor::of_loader(loader), Some(Flavor::Go));
+        assert!(Flavor::check_loader(Some(Flavor::Go), loader).is_ok());
+        assert!(Flavor::check_loader(Some(Flavor::Wasi), loader).is_err());
+    }
+}
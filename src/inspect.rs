@@ -0,0 +1,517 @@
+//! Recovers the polyglot structure of a packed file.
+//!
+//! This is the reverse direction of packing. We detect which target produced the file, undo the
+//! encoding of that target's stage 0 and then walk the sections of the embedded module.
+use core::{error::Error, ops::Range};
+use std::borrow::Cow;
+
+use crate::{
+    archive,
+    error::{InvalidModuleError, UnrecognizedDocumentError},
+    Compression, Encoding, Target,
+};
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const TAR_MAGIC: &[u8] = b"ustar ";
+const PDF_MAGIC: &[u8] = b"%PDF-";
+const SH_MAGIC: &[u8] = b"#!/bin/sh\n";
+const TAR_BOOT: &str = "boot/wah-init.wasm";
+const TAR_BASE122: &[u8] = b" data-encoding=\"base122\"";
+const DATA_TYPE_START: &[u8] = b"<template id=\"wah_data_type\">";
+const DATA_CHUNK_START: &[u8] = b"<template class=\"wah_data_chunk\">";
+/// Documents before chunking stored the module in one data URI.
+const DATA_URI_START: &[u8] = b"<template id=\"wah_data_uri\">";
+const TEMPLATE_END: &[u8] = b"</template>";
+/// The template of `html+zip` that holds the zip archive behind the page.
+const ZIP_TEMPLATE_START: &[u8] = b"<template class=\"wah_polyglot_zip\">";
+
+const ZIP_LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+/// The first entry of an EPUB, with its contents, after the local header.
+const EPUB_MIMETYPE: &[u8] = b"mimetypeapplication/epub+zip";
+const EPUB_MODULE: &str = "EPUB/wah-init.wasm";
+const ZIP_END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+
+/// The polyglot structure of a packed file, as the `inspect` command reports it.
+pub struct Document<'data> {
+    /// The target that produced the file, told from its first bytes.
+    pub target: Target,
+    /// Where the module is stored within the file, in its encoded form.
+    pub module_span: Range<usize>,
+    /// The module itself, decoded from its embedding.
+    pub module: Cow<'data, [u8]>,
+    /// For `html` and `html+zip`, how the module was compressed before encoding it.
+    pub compression: Option<Compression>,
+    /// For `html`, `html+tar` and `html+zip`, how the module is encoded as text.
+    pub encoding: Option<Encoding>,
+    /// All sections of the module in order, with ranges relative to the module.
+    pub sections: Vec<Section>,
+    /// For `html+tar`, all named entries of the tar archive.
+    pub tar_entries: Vec<TarEntry>,
+}
+
+/// A section of the embedded module.
+pub struct Section {
+    /// The section id, `0` for custom sections.
+    pub id: u8,
+    /// The name of a custom section.
+    pub name: Option<String>,
+    /// The byte range of the section's contents within the module.
+    pub range: Range<usize>,
+}
+
+/// An entry of the tar archive of an `html+tar` file.
+pub struct TarEntry {
+    /// The path of the entry within the archive.
+    pub name: String,
+    /// The byte range of the entry's (encoded) contents within the file.
+    pub range: Range<usize>,
+}
+
+impl<'data> Document<'data> {
+    /// Recognize the target of a file and recover its module and sections.
+    pub fn new(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        if data.starts_with(WASM_MAGIC) {
+            let mut document = Self::with_module(
+                Target::WasmPlusHtml,
+                0..data.len(),
+                Cow::Borrowed(data),
+                vec![],
+            )?;
+
+            // The `pdf` target differs only in the header that starts its stage 0.
+            let stage0 = document.sections.iter().find(|section| {
+                section.name.as_deref() == Some("wah_polyglot_stage0")
+                    && data[section.range.clone()].starts_with(PDF_MAGIC)
+            });
+
+            if stage0.is_some() {
+                document.target = Target::PdfPlusWasm;
+            }
+
+            return Ok(document);
+        }
+
+        if data.get(257..263) == Some(TAR_MAGIC) {
+            return Self::from_tar(data);
+        }
+
+        if data.starts_with(SH_MAGIC) {
+            // The script ends where the module starts, it contains no other zero bytes.
+            let start = find(data, WASM_MAGIC)
+                .ok_or_else(|| unrecognized("the `sh` script is not followed by a module"))?;
+
+            return Self::with_module(
+                Target::ShPlusWasm,
+                start..data.len(),
+                Cow::Borrowed(&data[start..]),
+                vec![],
+            );
+        }
+
+        if data.starts_with(ZIP_LOCAL_HEADER)
+            && data
+                .get(30..)
+                .is_some_and(|rest| rest.starts_with(EPUB_MIMETYPE))
+        {
+            return Self::from_epub(data);
+        }
+
+        if find(data, DATA_TYPE_START).is_some() {
+            return Self::from_data_chunks(data);
+        }
+
+        if find(data, DATA_URI_START).is_some() {
+            return Self::from_data_uri(data);
+        }
+
+        Err(unrecognized(
+            "neither a WebAssembly module, an `html+tar` archive, an EPUB nor an `html` page with a module",
+        ))
+    }
+
+    fn from_data_chunks(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        let (type_span, _) = template_contents(data, DATA_TYPE_START)
+            .ok_or_else(|| unrecognized("the data type template is not closed"))?;
+        let prefix = &data[type_span.clone()];
+
+        let mut chunks = vec![];
+        let mut module_span = type_span.end..type_span.end;
+
+        while let Some((span, rest)) = template_contents(&data[module_span.end..], DATA_CHUNK_START)
+        {
+            let offset = module_span.end;
+            if chunks.is_empty() {
+                module_span.start = offset + span.start;
+            }
+
+            chunks.push(&data[offset + span.start..offset + span.end]);
+            module_span.end = offset + rest;
+        }
+
+        let mut document = Self::from_encoded(prefix, module_span, &chunks)?;
+        if find(&data[document.module_span.end..], ZIP_TEMPLATE_START).is_some() {
+            document.target = Target::HtmlPlusZip;
+        }
+
+        Ok(document)
+    }
+
+    fn from_data_uri(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        let (span, _) = template_contents(data, DATA_URI_START)
+            .ok_or_else(|| unrecognized("the data URI template is not closed"))?;
+
+        let uri = &data[span.clone()];
+        let comma = uri
+            .iter()
+            .position(|&b| b == b',')
+            .ok_or_else(|| unrecognized("the data URI has no payload"))?;
+
+        let module_span = span.start + comma + 1..span.end;
+        Self::from_encoded(&uri[..=comma], module_span.clone(), &[&data[module_span]])
+    }
+
+    /// Decode the payload of a data URI, with its prefix such as `data:application/gzip;base64,`.
+    ///
+    /// Each chunk of the payload was encoded on its own.
+    fn from_encoded(
+        prefix: &[u8],
+        module_span: Range<usize>,
+        chunks: &[&[u8]],
+    ) -> Result<Self, Box<dyn Error>> {
+        // The media type names the compression, the parameter the encoding.
+        let prefix = String::from_utf8_lossy(prefix);
+        let mut parts = prefix
+            .trim_start_matches("data:")
+            .trim_end_matches(',')
+            .split(';');
+        let compression = parts.next().and_then(Compression::from_media_type);
+        let encoding = match parts.next() {
+            Some(encoding) => encoding.parse::<Encoding>()?,
+            None => Encoding::Base64,
+        };
+
+        let mut module = vec![];
+        for chunk in chunks {
+            module.extend(encoding.decode(chunk)?);
+        }
+
+        if let Some(compression) = compression {
+            module = compression.decompress(&module)?;
+        }
+
+        let mut document =
+            Self::with_module(Target::Html, module_span, Cow::Owned(module), vec![])?;
+        document.compression = compression;
+        document.encoding = Some(encoding);
+        Ok(document)
+    }
+
+    fn from_tar(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        let mut tar_entries = vec![];
+        let mut offset = 0;
+
+        while let Some(header) = data.get(offset..offset + 512) {
+            // The archive ends with blocks of zeros.
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            let size = parse_octal(&header[124..136])
+                .ok_or_else(|| unrecognized("a tar header has an invalid size"))?;
+            let start = offset + 512;
+            let end = start + size;
+
+            // Entries escaping into and out of the HTML have empty names.
+            let name = until_nul(&header[..100]);
+            if !name.is_empty() {
+                tar_entries.push(TarEntry {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    range: start..end,
+                });
+            }
+
+            offset = end.next_multiple_of(512);
+        }
+
+        let boot = tar_entries
+            .iter()
+            .find(|entry| entry.name == TAR_BOOT)
+            .ok_or_else(|| unrecognized("the tar archive has no `boot/wah-init.wasm` entry"))?;
+
+        let module_span = boot.range.clone();
+        let encoded = data
+            .get(module_span.clone())
+            .ok_or_else(|| unrecognized("the boot entry extends past the end of file"))?;
+
+        // The escaping header before the entry's own opens the template with its encoding.
+        let escape = module_span.start.saturating_sub(1024)..module_span.start.saturating_sub(512);
+        let encoding = match find(&data[escape], TAR_BASE122) {
+            Some(_) => Encoding::Base122,
+            None => Encoding::Base64,
+        };
+
+        let module = encoding.decode(encoded)?;
+
+        let mut document = Self::with_module(
+            Target::HtmlPlusTar,
+            module_span,
+            Cow::Owned(module),
+            tar_entries,
+        )?;
+        document.encoding = Some(encoding);
+        Ok(document)
+    }
+
+    fn from_epub(data: &'data [u8]) -> Result<Self, Box<dyn Error>> {
+        use std::io::Read as _;
+
+        let mut epub = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+        let mut entry = epub
+            .by_name(EPUB_MODULE)
+            .map_err(|_| unrecognized("the EPUB has no `EPUB/wah-init.wasm` resource"))?;
+
+        let start = entry.data_start() as usize;
+        let module_span = start..start + entry.compressed_size() as usize;
+
+        let mut module = vec![];
+        entry.read_to_end(&mut module)?;
+
+        Self::with_module(Target::Epub, module_span, Cow::Owned(module), vec![])
+    }
+
+    fn with_module(
+        target: Target,
+        module_span: Range<usize>,
+        module: Cow<'data, [u8]>,
+        tar_entries: Vec<TarEntry>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut sections = vec![];
+
+        for payload in wasmparser::Parser::default().parse_all(&module) {
+            let payload = payload.map_err(InvalidModuleError::from)?;
+
+            if let wasmparser::Payload::CustomSection(custom) = &payload {
+                sections.push(Section {
+                    id: 0,
+                    name: Some(custom.name().to_string()),
+                    range: custom.data_offset()..custom.range().end,
+                });
+            } else if let Some((id, range)) = payload.as_section() {
+                sections.push(Section {
+                    id,
+                    name: None,
+                    range,
+                });
+            }
+        }
+
+        Ok(Document {
+            target,
+            module_span,
+            module,
+            compression: None,
+            encoding: None,
+            sections,
+            tar_entries,
+        })
+    }
+
+    /// Whether the module only holds the loaders and a sealed inner module, see [`crate::seal`].
+    pub fn is_encrypted(&self) -> bool {
+        self.sections
+            .iter()
+            .any(|section| section.name.as_deref() == Some(crate::seal::SECTION))
+    }
+
+    /// The section of the data archive, if the document was packed with one.
+    ///
+    /// That is the `wah_polyglot_stage2_data` section, unless the archive was renamed with
+    /// `--trailing-zip-section`. A renamed archive is only recognized as the trailing section of a
+    /// packed document, with its offsets relocated to its place. A zip that was merely added as a
+    /// section, or that ends a plain module, counts from its own start instead.
+    pub fn archive(&self) -> Option<&Section> {
+        let named = |section: &Section, name: &str| section.name.as_deref() == Some(name);
+
+        if let Some(section) = self
+            .sections
+            .iter()
+            .find(|section| named(section, "wah_polyglot_stage2_data"))
+        {
+            return Some(section);
+        }
+
+        if !self
+            .sections
+            .iter()
+            .any(|section| named(section, "wah_polyglot_stage0"))
+        {
+            return None;
+        }
+
+        // The PDF follows the archive in the `pdf` target.
+        let trailing = self
+            .sections
+            .iter()
+            .rev()
+            .find(|section| !named(section, "wah_polyglot_pdf"))?;
+        let data = &self.module[trailing.range.clone()];
+
+        let is_archive = trailing.name.is_some() && is_zip(data) && archive::is_relocated(data);
+        is_archive.then_some(trailing)
+    }
+
+    /// Describe the role that a section plays in the polyglot structure.
+    pub fn describe(&self, section: &Section) -> &'static str {
+        let Some(name) = &section.name else {
+            return standard_section_name(section.id);
+        };
+
+        match name.as_str() {
+            "wah_polyglot_stage0" => "stage0 loader",
+            "wah_polyglot_stage1" => "stage1 loader",
+            "wah_polyglot_stage1_html" => "index HTML",
+            "wah_polyglot_stage2" => "stage2 loader",
+            "wah_polyglot_stage2_data" => "data archive",
+            "wah_polyglot_wasm_bindgen" => "wasm-bindgen glue",
+            "wah_polyglot_emscripten" => "Emscripten glue",
+            "wah_polyglot_go" => "Go wasm_exec.js",
+            "wah_polyglot_stage3" => "stage3 loader",
+            "wah_polyglot_pdf" => "PDF document",
+            "wah_polyglot_signature" => "signature",
+            "wah_polyglot_encrypted" => "encrypted module",
+            "wah_wasi_config" => "WASI configuration",
+            // The data archive may have been renamed with `--trailing-zip-section`.
+            _ if self
+                .archive()
+                .is_some_and(|archive| archive.range == section.range) =>
+            {
+                "data archive"
+            }
+            name if name.starts_with("wah_polyglot_") => "polyglot section",
+            _ => "custom section",
+        }
+    }
+
+    /// Write the human-readable report of the `inspect` command.
+    pub fn report(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        writeln!(out, "target: {}", self.target)?;
+
+        match self.target {
+            Target::WasmPlusHtml | Target::PdfPlusWasm => {}
+            Target::ShPlusWasm => {
+                writeln!(
+                    out,
+                    "module: {} bytes, after a script of {} bytes",
+                    self.module.len(),
+                    self.module_span.start,
+                )?;
+            }
+            Target::Html | Target::HtmlPlusTar | Target::HtmlPlusZip | Target::Epub => {
+                writeln!(
+                    out,
+                    "module: {} bytes, encoded at {}..{} of the file",
+                    self.module.len(),
+                    self.module_span.start,
+                    self.module_span.end,
+                )?;
+
+                if let Some(encoding) = self.encoding {
+                    writeln!(out, "encoding: {encoding}")?;
+                }
+
+                if let Some(compression) = self.compression {
+                    writeln!(out, "compression: {compression}")?;
+                }
+            }
+        }
+
+        if !self.tar_entries.is_empty() {
+            writeln!(out, "tar entries:")?;
+            writeln!(out, "{:>10} {:>10}  name", "offset", "size")?;
+
+            for entry in &self.tar_entries {
+                writeln!(
+                    out,
+                    "{:>10} {:>10}  {}",
+                    entry.range.start,
+                    entry.range.len(),
+                    entry.name,
+                )?;
+            }
+        }
+
+        writeln!(out, "sections:")?;
+        writeln!(out, "{:>10} {:>10}  section", "offset", "size")?;
+
+        for section in &self.sections {
+            let what = self.describe(section);
+            write!(
+                out,
+                "{:>10} {:>10}  ",
+                section.range.start,
+                section.range.len()
+            )?;
+
+            match &section.name {
+                Some(name) => writeln!(out, "custom `{name}`: {what}")?,
+                None => writeln!(out, "{what}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn standard_section_name(id: u8) -> &'static str {
+    match id {
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "data count",
+        13 => "tag",
+        _ => "unknown",
+    }
+}
+
+/// Whether data starts as a zip archive, or as an empty one.
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(ZIP_LOCAL_HEADER) || data.starts_with(ZIP_END_OF_CENTRAL_DIRECTORY)
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The span of a template's contents, and the end of the template, searching from its start tag.
+fn template_contents(data: &[u8], start_tag: &[u8]) -> Option<(Range<usize>, usize)> {
+    let start = find(data, start_tag)? + start_tag.len();
+    let end = start + find(&data[start..], TEMPLATE_END)?;
+    Some((start..end, end + TEMPLATE_END.len()))
+}
+
+pub(crate) fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+pub(crate) fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = until_nul(field);
+    let digits = core::str::from_utf8(digits).ok()?.trim();
+    usize::from_str_radix(digits, 8).ok()
+}
+
+fn unrecognized(reason: &'static str) -> Box<dyn Error> {
+    Box::new(UnrecognizedDocumentError {
+        reason: reason.into(),
+    })
+}
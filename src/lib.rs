@@ -0,0 +1,1316 @@
+//! A packer that adds a webpage to a WebAssembly module, making it self-hosted.
+//!
+//! The [`Packer`] wraps a module into one of the polyglot [`Target`]s, with all inputs and the
+//! output given as bytes. This is the library underneath the `wasm-as-html` command line, for use
+//! in build scripts.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let module = std::fs::read("todomvc_bg.wasm")?;
+//!
+//! let html = wasm_as_html::Packer::new(std::fs::read("todomvc.js")?)
+//!     .index_html(std::fs::read("index.html")?)
+//!     .target(wasm_as_html::Target::Html)
+//!     .pack(&module)?;
+//! # Ok(()) }
+//! ```
+use core::{error::Error, ops::Range};
+use std::borrow::Cow;
+
+pub mod archive;
+pub mod bindgen;
+pub mod check;
+#[cfg(any(feature = "target-html+tar", feature = "target-epub"))]
+mod dom;
+pub mod emscripten;
+#[cfg(feature = "target-epub")]
+mod epub;
+pub mod error;
+pub mod go;
+pub mod inspect;
+pub mod manifest;
+pub mod pdf;
+#[cfg(feature = "run")]
+pub mod run;
+pub mod seal;
+pub mod sign;
+pub mod stage2;
+pub mod unpack;
+
+pub use html_and_tar::Encoding;
+
+/// Sections from a previous pack that are always written anew, at their fixed position.
+const REPLACED_SECTIONS: &[&str] = &[
+    "wah_polyglot_stage1_html",
+    "wah_polyglot_stage2",
+    "wah_polyglot_pdf",
+];
+
+/// Where stage 0 templates take the password prompt of encrypted documents.
+const UNSEAL_PLACEHOLDER: &str = "/* __REPLACE_THIS_WITH_UNSEAL__ */";
+/// Where stage 1 templates take the signature verification they share.
+const VERIFY_PLACEHOLDER: &str = "/* __REPLACE_THIS_WITH_VERIFY__ */";
+
+/// The bytes of the module encoded into each data chunk of the `html` target.
+///
+/// A multiple of 3 so that every chunk is base64 without padding, and comfortably below the 32MB
+/// limit of a data URI in Firefox.
+const HTML_CHUNK_LEN: usize = 3 << 20;
+
+const HTML_CHUNK_START: &[u8] = b"<template class=\"wah_data_chunk\">";
+
+/// The template behind the `html` page of `html+zip`, holding the data archive.
+///
+/// Its end tag is the comment of the archive, so that the archive extends to the end of the file.
+const HTML_ZIP_START: &[u8] = b"<template class=\"wah_polyglot_zip\">";
+const HTML_ZIP_END: &str = "</template>\n";
+
+/// Digits reserved for the offset of the module in the `sh` preamble, padded with spaces.
+const SH_OFFSET_DIGITS: usize = 10;
+
+/// Configuration of the sections and the target to pack a module into.
+#[derive(Clone, Debug)]
+pub struct Packer {
+    /// The stage 2 loader, picked for the module if there is none.
+    stage_2: Option<Vec<u8>>,
+    index_html: Option<Vec<u8>>,
+    extra_sections: Vec<(String, Vec<u8>)>,
+    zip: Option<Vec<u8>>,
+    zip_section_name: Option<String>,
+    pdf: Option<Vec<u8>>,
+    signing_key: Option<Vec<u8>>,
+    signer: String,
+    password: Option<String>,
+    target: Target,
+    compression: Option<Compression>,
+    encoding: Encoding,
+    validate: bool,
+    edit: bool,
+}
+
+/// How to wrap the output Web Assembly module.
+///
+/// This determines the 'stage 0' entry point into setting up the web assembly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Enters execution from an initial section that looks like valid HTML.
+    ///
+    /// This target is NOT compatible with serving from a file in Chromium, as it requires access
+    /// to the file's own file-URI via `fetch`.
+    #[default]
+    WasmPlusHtml,
+    /// Encodes the resulting module as a blob and loads it.
+    ///
+    /// This target is generally compatible with web browsers but obviously the output file is no
+    /// longer a WebAssembly module itself.
+    Html,
+    /// Embeds the module into the index HTML such that the file is also a tar archive.
+    HtmlPlusTar,
+    /// Like `html`, but the file is also a zip archive of the files in the data archive.
+    ///
+    /// The entries are hidden in a template behind the page, the central directory ends the file.
+    HtmlPlusZip,
+    /// Packages the module into an EPUB publication, with the index HTML as its chapter.
+    ///
+    /// Readers with scripting load the module from the chapter, others show it as it is.
+    Epub,
+    /// Like `wasm`, but preceded by a `sh` script that runs the module with a WASI runtime.
+    ///
+    /// The script hides in an HTML comment, so the file is not a module itself but still a page.
+    ShPlusWasm,
+    /// Like `wasm`, but the file is also the PDF document given to the packer.
+    ///
+    /// PDF readers find the header in stage 0 and the document in the last section.
+    PdfPlusWasm,
+}
+
+/// How to compress the module for the `html` target.
+///
+/// The names are the formats of the browser's `DecompressionStream`, which the stage 0 loader uses
+/// to inflate the module again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// The gzip format.
+    Gzip,
+    /// The zlib format, a deflate stream with a small header and checksum.
+    Deflate,
+}
+
+impl Packer {
+    /// Create a packer with the stage 2 loader payload, a JS module.
+    ///
+    /// The stage 2 payload is your module that gains control of execution and is invoked with a
+    /// fake request that resolves to full WASM module, after the page has been replaced with the
+    /// indicated index HTML.
+    pub fn new(stage_2: impl Into<Vec<u8>>) -> Self {
+        Packer {
+            stage_2: Some(stage_2.into()),
+            ..Packer::automatic()
+        }
+    }
+
+    /// A packer that picks a builtin stage 2 loader from the imports of the module.
+    ///
+    /// See [`stage2::Flavor`] for the modules it recognizes, packing others fails.
+    pub fn automatic() -> Self {
+        Packer {
+            stage_2: None,
+            index_html: None,
+            extra_sections: vec![],
+            zip: None,
+            zip_section_name: None,
+            pdf: None,
+            signing_key: None,
+            signer: String::new(),
+            password: None,
+            target: Target::default(),
+            compression: None,
+            encoding: Encoding::default(),
+            validate: false,
+            edit: false,
+        }
+    }
+
+    /// An HTML page to use when invoking the loader.
+    ///
+    /// Setup by the stage 1 loader. Defaults to an empty page that hides some garbage from
+    /// processing the WASM module header. The `html+tar` target also embeds the module into this
+    /// page.
+    pub fn index_html(mut self, html: impl Into<Vec<u8>>) -> Self {
+        self.index_html = Some(html.into());
+        self
+    }
+
+    /// Add a custom section after the sections of the module.
+    pub fn section(mut self, name: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        self.extra_sections.push((name.into(), data.into()));
+        self
+    }
+
+    /// A zip file to attach.
+    ///
+    /// This file is added as a final section of the module (so its central archive is within the
+    /// last 512 bytes). Its offsets are rewritten such that the `wasm` output is also a valid zip
+    /// archive.
+    pub fn trailing_zip(mut self, zip: impl Into<Vec<u8>>) -> Self {
+        self.zip = Some(zip.into());
+        self
+    }
+
+    /// A customized section name to use for the final zip section.
+    ///
+    /// The section is named `wah_polyglot_stage2_data` by default.
+    pub fn trailing_zip_section(mut self, name: impl Into<String>) -> Self {
+        self.zip_section_name = Some(name.into());
+        self
+    }
+
+    /// A PDF document that the `pdf` target file opens as.
+    ///
+    /// The document is added as the `wah_polyglot_pdf` section, with its offsets rewritten. It must
+    /// use a classic cross-reference table without incremental updates.
+    pub fn pdf(mut self, pdf: impl Into<Vec<u8>>) -> Self {
+        self.pdf = Some(pdf.into());
+        self
+    }
+
+    /// Sign the document with an ed25519 private key, in PKCS#8 PEM form.
+    ///
+    /// The signature covers the whole module, its loaders, the index HTML and the data archive.
+    /// The stage 1 loader refuses to continue with a document that was modified afterwards, and
+    /// shows the signer otherwise. See [`sign`] for the format.
+    pub fn signing_key(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.signing_key = Some(pem.into());
+        self
+    }
+
+    /// Who signs the document, such as a name and email address, shown next to the key.
+    pub fn signer(mut self, identity: impl Into<String>) -> Self {
+        self.signer = identity.into();
+        self
+    }
+
+    /// Encrypt the document with a password, which stage 0 asks for.
+    ///
+    /// Only the stage 0 and stage 1 loaders remain readable, see [`seal`]. Requires the `wasm`,
+    /// `html` or `html+tar` target. A `wasm` document is then no longer a module that runs by
+    /// itself, nor a zip archive of its data, only a page.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// How to wrap the output module, see [`Target`]. Defaults to `wasm`.
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Compress the module before it is encoded by the `html` target.
+    ///
+    /// Modules with a lot of text or an attached archive shrink considerably. Other targets keep
+    /// the module readable as it is and reject compression.
+    pub fn compress(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// How the `html` and `html+tar` targets encode the module as text.
+    ///
+    /// Defaults to base64. The denser base122 requires that the document is read as UTF-8, which
+    /// the `html` target declares itself and the index HTML must declare for `html+tar`.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Fully validate the module before packing.
+    ///
+    /// Rejects modules that parse but would later fail `WebAssembly.compileStreaming` in the stage
+    /// 0 loader. Proposals that are not enabled by default in browsers are not accepted.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Experimental. Replace stage 1 with a driver that hot-reloads the file when it changes.
+    pub fn experimental_edit(mut self, edit: bool) -> Self {
+        self.edit = edit;
+        self
+    }
+
+    /// Pack a module, returning the bytes of the output file.
+    ///
+    /// The module may itself be a document from a previous pack, of any target. Its sections
+    /// added by the packer are then replaced by the ones configured here, and retained where
+    /// nothing new was configured. This allows updating only the loader or the index HTML.
+    pub fn pack(&self, wasm: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.check_options()?;
+
+        // Anything that is not recognized as a document is reported by the parser below.
+        let document = inspect::Document::new(wasm).ok();
+        if document
+            .as_ref()
+            .is_some_and(inspect::Document::is_encrypted)
+        {
+            return Err("the module is encrypted, pack the original module instead")?;
+        }
+
+        let previous = document.as_ref().map(unpack::unpack);
+        let wasm = previous.as_ref().map_or(wasm, |previous| &previous.module);
+
+        if self.validate {
+            wasmparser::Validator::new()
+                .validate_all(wasm)
+                .map_err(error::InvalidModuleError::from)?;
+        }
+
+        let inputs = self.inputs(previous.as_ref())?;
+        let signing_key = self
+            .signing_key
+            .as_deref()
+            .map(sign::signing_key)
+            .transpose()?;
+
+        let (loader, moved_glue) = self.select_stage_2(wasm)?;
+        let mut extra_sections = self.extra_sections(previous.as_ref(), moved_glue);
+
+        let section_glue = SectionGlue::new(&extra_sections)?;
+        section_glue.replace_sections(&mut extra_sections);
+        let data_section = inputs.zip.map(|(name, _)| name);
+        let (stage_2, convention) =
+            self.adapt_stage_2(loader, &section_glue, &extra_sections, data_section)?;
+
+        // Fixes the length of the PDF, it is relocated once its position is known.
+        let pdf = inputs.pdf.map(|pdf| pdf::relocate(pdf, 0)).transpose()?;
+
+        // Everything before the module, which the offsets of the trailing sections count from.
+        let preamble = match self.target {
+            Target::ShPlusWasm => Self::sh_preamble(inputs.zip.is_some()),
+            _ => vec![],
+        };
+
+        let sealed = self.password.is_some();
+        let stage0 = with_unseal(include_str!("stage0-wasm.html"), sealed);
+        let stage0 = match self.target {
+            Target::PdfPlusWasm => [pdf::HEADER, stage0.as_bytes()].concat(),
+            _ => stage0.into_bytes(),
+        };
+
+        let stage1 = with_verify(if self.edit {
+            include_str!("stage1-edit.js")
+        } else {
+            include_str!("stage1.js")
+        });
+        let stage1 = convention.apply(stage1.as_bytes());
+
+        let mut module = self.encode(Encoded {
+            wasm,
+            stage0: &stage0,
+            stage1: &stage1,
+            index_html: inputs.index_html,
+            stage_2: &stage_2,
+            signed: signing_key.is_some(),
+            extra_sections,
+            zip: inputs.zip,
+            pdf: pdf.as_deref(),
+            preamble_len: preamble.len(),
+        })?;
+
+        if let Some(key) = &signing_key {
+            sign::sign(&mut module, key)?;
+        }
+
+        // All of the module so far is sealed, the archive was relocated to its place within it.
+        if let Some(password) = &self.password {
+            let mut outer = wasm_encoder::Module::new();
+            Self::loader_sections(&mut outer, &stage0, &stage1);
+            outer.section(&wasm_encoder::CustomSection {
+                name: seal::SECTION,
+                data: &seal::seal(&module, password)?,
+            });
+
+            module = outer.finish();
+        }
+
+        self.wrap(module, preamble, &inputs)
+    }
+
+    /// Reject options that the target does not support, before any work is done.
+    fn check_options(&self) -> Result<(), Box<dyn Error>> {
+        if let (
+            Some(compression),
+            Target::WasmPlusHtml
+            | Target::HtmlPlusTar
+            | Target::Epub
+            | Target::ShPlusWasm
+            | Target::PdfPlusWasm,
+        ) = (self.compression, self.target)
+        {
+            return Err(format!(
+                "{compression} compression requires the `html` or `html+zip` target, not `{}`",
+                self.target
+            ))?;
+        }
+
+        if self.encoding != Encoding::Base64
+            && matches!(
+                self.target,
+                Target::WasmPlusHtml | Target::Epub | Target::ShPlusWasm | Target::PdfPlusWasm
+            )
+        {
+            return Err(format!(
+                "{} encoding requires the `html`, `html+tar` or `html+zip` target",
+                self.encoding
+            ))?;
+        }
+
+        if self.password.is_some()
+            && !matches!(
+                self.target,
+                Target::WasmPlusHtml | Target::Html | Target::HtmlPlusTar
+            )
+        {
+            return Err(format!(
+                "encryption requires the `wasm`, `html` or `html+tar` target, the `{}` target \
+                 keeps parts of the document outside the encrypted module",
+                self.target
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// The index HTML, data archive and PDF, as configured or retained from a previous pack.
+    fn inputs<'a>(
+        &'a self,
+        previous: Option<&unpack::Unpacked<'a>>,
+    ) -> Result<Inputs<'a>, Box<dyn Error>> {
+        let index_html = self
+            .index_html
+            .as_deref()
+            .or_else(|| previous?.section("wah_polyglot_stage1_html"));
+
+        let zip = match (&self.zip, previous.and_then(|previous| previous.archive)) {
+            (Some(data), _) => Some(("wah_polyglot_stage2_data", data.as_slice())),
+            (None, previous) => previous,
+        };
+        let zip = zip.map(|(default_name, data)| {
+            let name = self.zip_section_name.as_deref().unwrap_or(default_name);
+            (name, data)
+        });
+
+        let pdf = self
+            .pdf
+            .as_deref()
+            .or_else(|| previous?.section("wah_polyglot_pdf"));
+
+        match self.target {
+            Target::PdfPlusWasm if pdf.is_none() => {
+                Err("the `pdf` target requires a PDF document")?
+            }
+            Target::HtmlPlusZip if zip.is_none() => {
+                Err("the `html+zip` target requires a data archive")?
+            }
+            Target::HtmlPlusTar if index_html.is_none() => {
+                Err("the `html+tar` target embeds the module into the index HTML")?
+            }
+            Target::Epub if index_html.is_none() => {
+                Err("the `epub` target converts the index HTML into its chapter")?
+            }
+            _ => Ok(Inputs {
+                index_html,
+                zip,
+                pdf,
+            }),
+        }
+    }
+
+    /// The stage 2 loader, and glue that moves into a section for it.
+    ///
+    /// Emscripten glue and `wasm_exec.js` move into their sections, for our loaders to run.
+    fn select_stage_2(&self, wasm: &[u8]) -> Result<(&[u8], MovedGlue<'_>), Box<dyn Error>> {
+        let flavor = stage2::Flavor::of_module(wasm)?;
+
+        let Some(source) = self.stage_2.as_deref() else {
+            let loader = flavor
+                .ok_or(
+                    "no builtin stage 2 loader fits the imports of the module, name one instead",
+                )?
+                .builtin()
+                .source()?;
+            return Ok((loader, None));
+        };
+
+        let moved = if emscripten::glue(source).is_some() {
+            Some((stage2::Builtin::Emscripten, emscripten::SECTION))
+        } else if go::is_wasm_exec(source) {
+            Some((stage2::Builtin::Go, go::SECTION))
+        } else {
+            None
+        };
+
+        let loader = match moved {
+            Some((builtin, _)) => builtin.source()?,
+            None => source,
+        };
+
+        stage2::Flavor::check_loader(flavor, loader)?;
+        Ok((loader, moved.map(|(_, section)| (section, source))))
+    }
+
+    /// The sections added after the module, in order.
+    ///
+    /// Sections from a previous pack keep their order, unless replaced by name.
+    fn extra_sections<'a>(
+        &'a self,
+        previous: Option<&unpack::Unpacked<'a>>,
+        moved_glue: MovedGlue<'a>,
+    ) -> Vec<(&'a str, &'a [u8])> {
+        let mut extra_sections: Vec<(&str, &[u8])> = previous
+            .into_iter()
+            .flat_map(|previous| previous.added.iter().copied())
+            .filter(|(name, _)| !REPLACED_SECTIONS.contains(name))
+            .collect();
+        let previous_extra = extra_sections.len();
+
+        for (name, data) in &self.extra_sections {
+            let slot = extra_sections[..previous_extra]
+                .iter_mut()
+                .find(|(previous, _)| previous == name);
+
+            match slot {
+                Some(slot) => slot.1 = data,
+                None => extra_sections.push((name, data)),
+            }
+        }
+
+        if let Some((section, glue)) = moved_glue {
+            match extra_sections.iter_mut().find(|(name, _)| *name == section) {
+                Some(slot) => slot.1 = glue,
+                None => extra_sections.push((section, glue)),
+            }
+        }
+
+        extra_sections
+    }
+
+    /// Adapt wasm-bindgen glue as stage 2, or our loader to the glue it loads, and pass the
+    /// module to stage 2 as it expects.
+    ///
+    /// The `data_section` is the name of the data archive section, if there is one.
+    fn adapt_stage_2<'a>(
+        &self,
+        loader: &'a [u8],
+        section_glue: &SectionGlue<'_>,
+        extra_sections: &[(&str, &[u8])],
+        data_section: Option<&str>,
+    ) -> Result<AdaptedStage2<'a>, Box<dyn Error>> {
+        if loader == stage2::Builtin::BindgenWeb.source()? {
+            let glue = section_glue.bindgen.as_ref().ok_or_else(|| {
+                format!(
+                    "the `{}` loader requires the wasm-bindgen glue as the `{}` section",
+                    stage2::Builtin::BindgenWeb,
+                    stage2::BINDGEN_SECTION,
+                )
+            })?;
+
+            if let Some(import) = glue.imports.first() {
+                return Err(format!(
+                    "the wasm-bindgen glue imports `{import}`, which the `{}` loader does not \
+                     provide. Use a stage 2 loader that calls its `{}` hook",
+                    stage2::Builtin::BindgenWeb,
+                    bindgen::IMPORT_HOOK,
+                ))?;
+            }
+
+            let loader = glue.convention.apply(loader);
+            Ok((Cow::Owned(loader), bindgen::Convention::ModuleOrPath))
+        } else if loader == stage2::Builtin::Emscripten.source()? {
+            let glue = section_glue.emscripten.as_ref().ok_or_else(|| {
+                format!(
+                    "the `{}` loader requires the Emscripten glue as the `{}` section",
+                    stage2::Builtin::Emscripten,
+                    emscripten::SECTION,
+                )
+            })?;
+
+            if data_section.is_some() && !glue.exports_fs {
+                return Err(format!(
+                    "the Emscripten glue does not export `FS`, through which the `{}` loader \
+                     writes the data archive. Build it with `-sEXPORTED_RUNTIME_METHODS=FS`",
+                    stage2::Builtin::Emscripten,
+                ))?;
+            }
+
+            // Without an archive the loader finds no section, by any name.
+            let data_section = data_section.unwrap_or("wah_polyglot_stage2_data");
+            let loader = glue.kind.apply(loader, data_section);
+            Ok((Cow::Owned(loader), bindgen::Convention::ModuleOrPath))
+        } else if loader == stage2::Builtin::Go.source()? {
+            let section = |wanted: &str| {
+                extra_sections
+                    .iter()
+                    .find(|(name, _)| *name == wanted)
+                    .map(|(_, data)| *data)
+            };
+
+            if !section(go::SECTION).is_some_and(go::is_wasm_exec) {
+                return Err(format!(
+                    "the `{}` loader requires the `wasm_exec.js` of the toolchain as the `{}` \
+                     section",
+                    stage2::Builtin::Go,
+                    go::SECTION,
+                ))?;
+            }
+
+            let loader = go::apply(loader, section(go::CONFIG_SECTION))?;
+            Ok((Cow::Owned(loader), bindgen::Convention::ModuleOrPath))
+        } else if let Some(glue) = bindgen::glue(loader)? {
+            if let Some(import) = glue.imports.first() {
+                return Err(format!(
+                    "the wasm-bindgen glue imports `{import}`, which stage 1 does not provide. \
+                     Pack it as the `{}` section for a loader that calls its `{}` hook",
+                    stage2::BINDGEN_SECTION,
+                    bindgen::IMPORT_HOOK,
+                ))?;
+            }
+
+            Ok((glue.source, glue.convention))
+        } else if self.edit || stage2::Flavor::of_loader(loader) == Some(stage2::Flavor::Wasi) {
+            // Other loaders keep what each stage 1 passed before, the WASI loader takes `input`.
+            Ok((Cow::Borrowed(loader), bindgen::Convention::Input))
+        } else {
+            Ok((Cow::Borrowed(loader), bindgen::Convention::ModuleOrPath))
+        }
+    }
+
+    /// Encode the module with all sections, before it is signed, sealed and wrapped.
+    ///
+    /// The offsets of the trailing archive and PDF are made to count from the start of the file.
+    fn encode(&self, parts: Encoded<'_>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut encoder = wasm_encoder::Module::new();
+
+        // An encrypted document has its loaders in an outer module, see `pack`.
+        if self.password.is_none() {
+            Self::loader_sections(&mut encoder, parts.stage0, parts.stage1);
+        }
+
+        if let Some(index_html) = parts.index_html {
+            encoder.section(&wasm_encoder::CustomSection {
+                name: "wah_polyglot_stage1_html",
+                data: index_html,
+            });
+        }
+
+        encoder.section(&wasm_encoder::CustomSection {
+            name: "wah_polyglot_stage2",
+            data: parts.stage_2,
+        });
+
+        // Reserved with a fixed size, such that signing does not move the sections after it.
+        if parts.signed {
+            encoder.section(&wasm_encoder::CustomSection {
+                name: sign::SECTION,
+                data: &sign::placeholder(&self.signer),
+            });
+        }
+
+        for section in wasmparser::Parser::default().parse_all(parts.wasm) {
+            if let Some((id, data_range)) = section
+                .map_err(error::InvalidModuleError::from)?
+                .as_section()
+            {
+                encoder.section(&wasm_encoder::RawSection {
+                    id,
+                    data: &parts.wasm[data_range],
+                });
+            }
+        }
+
+        for (name, data) in parts.extra_sections {
+            encoder.section(&wasm_encoder::CustomSection { name, data });
+        }
+
+        // Readers of both zip and PDF search from the end of the file. The PDF ends its own target.
+        let (zip_span, pdf_span);
+        let (zip, pdf) = (parts.zip, parts.pdf);
+        if self.target == Target::PdfPlusWasm {
+            zip_span = zip.map(|(name, data)| trailing_section(&mut encoder, name, data));
+            pdf_span = pdf.map(|pdf| trailing_section(&mut encoder, "wah_polyglot_pdf", pdf));
+        } else {
+            pdf_span = pdf.map(|pdf| trailing_section(&mut encoder, "wah_polyglot_pdf", pdf));
+            zip_span = zip.map(|(name, data)| trailing_section(&mut encoder, name, data));
+        }
+
+        let mut module = encoder.finish();
+
+        if let Some(span) = zip_span {
+            archive::relocate(&mut module[span.clone()], parts.preamble_len + span.start)?;
+        }
+
+        if let Some(span) = pdf_span {
+            let pdf = pdf::relocate(&module[span.clone()], parts.preamble_len + span.start)?;
+            module[span].copy_from_slice(&pdf);
+        }
+
+        Ok(module)
+    }
+
+    /// Wrap the module into the target.
+    fn wrap(
+        &self,
+        module: Vec<u8>,
+        preamble: Vec<u8>,
+        inputs: &Inputs<'_>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let sealed = self.password.is_some();
+
+        match self.target {
+            Target::WasmPlusHtml | Target::PdfPlusWasm => Ok(module),
+            Target::ShPlusWasm => Ok([preamble, module].concat()),
+            Target::Html => Ok(Self::pack_html(
+                module,
+                self.compression,
+                self.encoding,
+                sealed,
+            )),
+            Target::HtmlPlusTar => {
+                Self::pack_html_plus_tar(inputs.index_html, module, self.encoding, sealed)
+            }
+            Target::HtmlPlusZip => {
+                let Some((_, zip)) = inputs.zip else {
+                    return Err("the `html+zip` target requires a data archive")?;
+                };
+
+                let html = Self::pack_html(module, self.compression, self.encoding, false);
+                Self::pack_html_plus_zip(html, zip)
+            }
+            Target::Epub => {
+                Self::pack_epub(inputs.index_html, &module, inputs.zip.map(|(_, zip)| zip))
+            }
+        }
+    }
+
+    fn loader_sections(encoder: &mut wasm_encoder::Module, stage0: &[u8], stage1: &[u8]) {
+        encoder.section(&wasm_encoder::CustomSection {
+            name: "wah_polyglot_stage0",
+            // Html designed to terminate processing into further WASM sections. This is the only
+            // section that needs to be placed specifically at the start. All other sections are
+            // then parsed from the module.
+            data: stage0,
+        });
+
+        // The actual (document) loader that prepares inputs and control for stage 2.
+        encoder.section(&wasm_encoder::CustomSection {
+            name: "wah_polyglot_stage1",
+            data: stage1,
+        });
+    }
+
+    fn pack_html(
+        wasm: Vec<u8>,
+        compression: Option<Compression>,
+        encoding: Encoding,
+        sealed: bool,
+    ) -> Vec<u8> {
+        let template = with_unseal(include_str!("stage0-html.html"), sealed);
+
+        let (wasm, media_type, content_encoding) = match compression {
+            None => (wasm, "application/octet-stream", String::new()),
+            Some(compression) => (
+                compression.compress(&wasm),
+                compression.media_type(),
+                compression.to_string(),
+            ),
+        };
+
+        let template =
+            template.replace("__REPLACE_THIS_WITH_CONTENT_ENCODING__", &content_encoding);
+
+        // To include our WebAssembly module as data, we need to massage the data into an HTML
+        // compatible form. In the end, access to it as an ArrayBuffer is required. The pure
+        // `fetch` is sometimes limited by the browser so maybe that's a problem? But it is the
+        // most efficient base64 decoder we have. And it is **correct**.
+        //
+        // <https://stackoverflow.com/questions/21797299/convert-base64-string-to-arraybuffer>
+        // There answers are mostly bad, and confidently incorrect.
+        //
+        // Firefox restricts a data URI to 32MB when it is fetched, and large strings are a problem
+        // in all browsers. So the module is split into chunks that are each decoded on their own,
+        // and streamed into `WebAssembly.compileStreaming` by the stage 0 loader.
+        let (head, tail) = template
+            .split_once("__REPLACE_THIS_WITH_WASM_AS_DATA_CHUNKS__")
+            .expect("the stage 0 template has a place for chunks");
+
+        // With base122 the prefix only tells stage 0 to decode the chunks itself.
+        let head = head.replace(
+            "__REPLACE_THIS_WITH_DATA_URI_PREFIX__",
+            &format!("data:{media_type};{encoding},"),
+        );
+
+        let mut html = head.into_bytes();
+        for chunk in wasm.chunks(HTML_CHUNK_LEN) {
+            html.extend_from_slice(HTML_CHUNK_START);
+            html.extend_from_slice(&encoding.encode(chunk));
+            html.extend_from_slice(b"</template>");
+        }
+
+        html.extend_from_slice(tail.as_bytes());
+        html
+    }
+
+    /// The `sh` script before the module, which extracts and runs it.
+    ///
+    /// The offset of the module is padded to a fixed width, so it is the length of the script.
+    fn sh_preamble(has_archive: bool) -> Vec<u8> {
+        let script = include_str!("stage0-sh.sh").replace(
+            "__REPLACE_THIS_WITH_HAS_ARCHIVE__",
+            if has_archive { "1" } else { "0" },
+        );
+
+        let with_offset = |offset: usize| {
+            script.replace(
+                "__REPLACE_THIS_WITH_MODULE_OFFSET__",
+                &format!("{offset:<SH_OFFSET_DIGITS$}"),
+            )
+        };
+
+        with_offset(with_offset(0).len()).into_bytes()
+    }
+
+    /// Append the entries of the data archive to an `html` page, as a zip archive of the file.
+    fn pack_html_plus_zip(mut html: Vec<u8>, zip: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        // The archive of a previous pack counts its offsets from within that document.
+        let mut zip = zip.to_vec();
+        archive::relocate(&mut zip, 0)?;
+
+        let mut zip = archive::deflated(&zip, HTML_ZIP_END)?;
+
+        // Other markup is inert within the template, but its end tag would show the remaining
+        // bytes as text of the page. With deflated contents that only happens by chance.
+        let hidden = &zip[..zip.len() - HTML_ZIP_END.len()];
+        if hidden
+            .windows(b"</template".len())
+            .any(|window| window.eq_ignore_ascii_case(b"</template"))
+        {
+            return Err(
+                "the data archive contains `</template`, rename the entry or its contents",
+            )?;
+        }
+
+        html.extend_from_slice(HTML_ZIP_START);
+        archive::relocate(&mut zip, html.len())?;
+        html.extend_from_slice(&zip);
+        Ok(html)
+    }
+
+    #[cfg(not(feature = "target-epub"))]
+    fn pack_epub(_: Option<&[u8]>, _: &[u8], _: Option<&[u8]>) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err(error::UnsupportedFeatureError {
+            what_to_use: "target-epub".into(),
+            feature: "target-epub".into(),
+        })?
+    }
+
+    #[cfg(feature = "target-epub")]
+    fn pack_epub(
+        index_html: Option<&[u8]>,
+        module: &[u8],
+        zip: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Some(index_html) = index_html else {
+            return Err("the `epub` target converts the index HTML into its chapter")?;
+        };
+
+        let source = dom::SourceDocument::new(core::str::from_utf8(index_html)?);
+        let chapter = source.prepare_xhtml_chapter(epub::LOADER)?;
+
+        // The archive of a previous pack counts its offsets from within that document.
+        let archive = zip
+            .map(|zip| {
+                let mut zip = zip.to_vec();
+                archive::relocate(&mut zip, 0).map(|()| zip)
+            })
+            .transpose()?;
+
+        epub::Publication {
+            chapter: &chapter.xhtml,
+            title: chapter.title.as_deref().unwrap_or("WebAssembly document"),
+            language: chapter.language.as_deref().unwrap_or("und"),
+            module,
+            archive: archive.as_deref(),
+        }
+        .to_epub()
+    }
+
+    #[cfg(not(feature = "target-html+tar"))]
+    fn pack_html_plus_tar(
+        _: Option<&[u8]>,
+        _: Vec<u8>,
+        _: Encoding,
+        _: bool,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err(error::UnsupportedFeatureError {
+            what_to_use: "target-html+tar".into(),
+            feature: "target-html+tar".into(),
+        })?
+    }
+
+    #[cfg(feature = "target-html+tar")]
+    fn pack_html_plus_tar(
+        index_html: Option<&[u8]>,
+        binary_wasm: Vec<u8>,
+        encoding: Encoding,
+        sealed: bool,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Some(template) = index_html else {
+            return Err("the `html+tar` target embeds the module into the index HTML")?;
+        };
+
+        if encoding == Encoding::Base122 && !declares_utf8(template) {
+            return Err(
+                "the base122 encoding requires an index HTML with `<meta charset=\"utf-8\">`",
+            )?;
+        }
+
+        let source = core::str::from_utf8(template)?;
+        let mut source = dom::SourceDocument::new(source);
+        let source_script = with_unseal(include_str!("stage0-html_plus_tar.js"), sealed);
+
+        let structure = source.prepare_tar_structure()?;
+
+        let mut engine = html_and_tar::TarEngine::default();
+        let mut seq_of_bytes: Vec<&[u8]> = vec![];
+
+        let mut head_span = source.span(structure.html_tag);
+        head_span.end = head_span.start + structure.html_insertion_point;
+        head_span.start = 0;
+
+        let head = &source[head_span];
+        let where_to_insert = source.span(structure.insertion_tag);
+        let where_to_enter = source.span(structure.stage0);
+
+        if where_to_insert.end >= where_to_enter.start {
+            return Err(
+                "the index HTML must have the `WAH_POLYGLOT_HTML_PLUS_TAR_CONTENT` element before \
+                 the `WAH_POLYGLOT_HTML_PLUS_TAR_STAGE0` script",
+            )?;
+        }
+
+        let init = engine.start_of_file(head.as_bytes(), where_to_insert.start);
+        seq_of_bytes.push(init.header.as_bytes());
+        seq_of_bytes.push(init.extra.as_slice());
+        seq_of_bytes.push(source[init.consumed..where_to_insert.start].as_bytes());
+
+        let pushed_data = [engine.escaped_insert(
+            html_and_tar::Entry {
+                name: "boot/wah-init.wasm",
+                data: &binary_wasm,
+            },
+            encoding,
+        )];
+
+        for data in &pushed_data {
+            seq_of_bytes.push(data.padding);
+            seq_of_bytes.push(data.header.as_bytes());
+            seq_of_bytes.push(data.file.as_bytes());
+            seq_of_bytes.push(data.data.as_slice());
+        }
+
+        // FIXME: not sure if we should just do the open-end thing instead of EOF..
+
+        let eof = engine.escaped_eof();
+        seq_of_bytes.push(eof.padding);
+        seq_of_bytes.push(eof.header.as_bytes());
+        seq_of_bytes.push(eof.file.as_bytes());
+        seq_of_bytes.push(eof.data.as_slice());
+
+        seq_of_bytes.push(source[where_to_insert.end..where_to_enter.start].as_bytes());
+        seq_of_bytes.push(b"<script>");
+        seq_of_bytes.push(source_script.as_bytes());
+        seq_of_bytes.push(b"</script>");
+        seq_of_bytes.push(source[where_to_enter.end..].as_bytes());
+
+        Ok(seq_of_bytes.join(&b""[..]))
+    }
+}
+
+/// The pages and documents to pack, as configured or retained from a previous pack.
+struct Inputs<'a> {
+    index_html: Option<&'a [u8]>,
+    /// The data archive, with its section name.
+    zip: Option<(&'a str, &'a [u8])>,
+    pdf: Option<&'a [u8]>,
+}
+
+/// The stage 2 loader as it is packed, with the convention that stage 1 calls it with.
+type AdaptedStage2<'a> = (Cow<'a, [u8]>, bindgen::Convention);
+
+/// Glue given as stage 2, with the section that it moves into.
+type MovedGlue<'a> = Option<(&'static str, &'a [u8])>;
+
+/// Everything that goes into the module, see [`Packer::encode`].
+struct Encoded<'a> {
+    wasm: &'a [u8],
+    stage0: &'a [u8],
+    stage1: &'a [u8],
+    index_html: Option<&'a [u8]>,
+    stage_2: &'a [u8],
+    /// Whether to reserve the signature section.
+    signed: bool,
+    extra_sections: Vec<(&'a str, &'a [u8])>,
+    zip: Option<(&'a str, &'a [u8])>,
+    /// The PDF, relocated to the start of a file.
+    pdf: Option<&'a [u8]>,
+    /// The length of anything before the module in the file.
+    preamble_len: usize,
+}
+
+/// Glue that our loaders import from sections, adapted when packing.
+struct SectionGlue<'a> {
+    bindgen: Option<bindgen::Glue<'a>>,
+    emscripten: Option<emscripten::Glue<'a>>,
+}
+
+impl<'a> SectionGlue<'a> {
+    fn new(sections: &[(&str, &'a [u8])]) -> Result<Self, Box<dyn Error>> {
+        let section = |wanted: &str| {
+            sections
+                .iter()
+                .find(|(name, _)| *name == wanted)
+                .map(|&(_, data)| data)
+        };
+
+        // With the imports of wasm-bindgen glue rewritten into a hook for the loader.
+        let bindgen = match section(stage2::BINDGEN_SECTION) {
+            None => None,
+            Some(data) => Some(bindgen::glue(data)?.ok_or_else(|| {
+                format!(
+                    "the `{}` section holds no wasm-bindgen glue",
+                    stage2::BINDGEN_SECTION
+                )
+            })?),
+        };
+
+        let emscripten = match section(emscripten::SECTION) {
+            None => None,
+            Some(data) => Some(emscripten::glue(data).ok_or_else(|| {
+                format!(
+                    "the `{}` section holds no Emscripten glue",
+                    emscripten::SECTION
+                )
+            })?),
+        };
+
+        Ok(SectionGlue {
+            bindgen,
+            emscripten,
+        })
+    }
+
+    /// Pack the adapted glue in place of the original.
+    fn replace_sections<'s>(&'s self, sections: &mut [(&str, &'s [u8])]) {
+        for (name, data) in sections {
+            match (*name, &self.bindgen, &self.emscripten) {
+                (stage2::BINDGEN_SECTION, Some(glue), _) => *data = &glue.source,
+                (emscripten::SECTION, _, Some(glue)) => *data = &glue.source,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Put the password prompt of encrypted documents into a stage 0 template, or remove its place.
+fn with_unseal(template: &str, sealed: bool) -> String {
+    template
+        .split_inclusive('\n')
+        .map(|line| match line.contains(UNSEAL_PLACEHOLDER) {
+            false => line,
+            true if sealed => include_str!("stage0-unseal.js"),
+            true => "",
+        })
+        .collect()
+}
+
+fn with_verify(template: &str) -> String {
+    template.replace(
+        VERIFY_PLACEHOLDER,
+        include_str!("stage1-verify.js").trim_end(),
+    )
+}
+
+/// Add a custom section, returning the range of its contents in the module.
+fn trailing_section(encoder: &mut wasm_encoder::Module, name: &str, data: &[u8]) -> Range<usize> {
+    encoder.section(&wasm_encoder::CustomSection { name, data });
+    let end = encoder.as_slice().len();
+    end - data.len()..end
+}
+
+/// Whether the page declares its character set as UTF-8, in any of the usual spellings.
+#[cfg(feature = "target-html+tar")]
+fn declares_utf8(html: &[u8]) -> bool {
+    let html = String::from_utf8_lossy(html).to_ascii_lowercase();
+    let html: String = html.split_whitespace().collect();
+
+    ["charset=\"utf-8\"", "charset='utf-8'", "charset=utf-8"]
+        .iter()
+        .any(|declaration| html.contains(declaration))
+}
+
+impl core::fmt::Display for Target {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Target::WasmPlusHtml => "wasm",
+            Target::Html => "html",
+            Target::HtmlPlusTar => "html+tar",
+            Target::HtmlPlusZip => "html+zip",
+            Target::Epub => "epub",
+            Target::ShPlusWasm => "sh",
+            Target::PdfPlusWasm => "pdf",
+        })
+    }
+}
+
+impl Compression {
+    /// The media type of the data URI, which tells `inspect` how to undo the compression.
+    fn media_type(self) -> &'static str {
+        match self {
+            Compression::Gzip => "application/gzip",
+            Compression::Deflate => "application/zlib",
+        }
+    }
+
+    pub(crate) fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/gzip" => Some(Compression::Gzip),
+            "application/zlib" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        use std::io::Write as _;
+        let level = flate2::Compression::best();
+
+        // Writing into a vector can not fail.
+        match self {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(vec![], level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Read as _;
+        let mut out = vec![];
+
+        match self {
+            Compression::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut out)?,
+            Compression::Deflate => flate2::read::ZlibDecoder::new(data).read_to_end(&mut out)?,
+        };
+
+        Ok(out)
+    }
+}
+
+impl core::fmt::Display for Compression {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+        })
+    }
+}
+
+impl core::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "deflate" => Ok(Self::Deflate),
+            _ => Err(format!("Unknown compression {s}")),
+        }
+    }
+}
+
+impl core::str::FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wasm" => Ok(Self::WasmPlusHtml),
+            "wasm+html" => Ok(Self::WasmPlusHtml),
+            "html+tar" => Ok(Self::HtmlPlusTar),
+            "html+zip" => Ok(Self::HtmlPlusZip),
+            "epub" => Ok(Self::Epub),
+            "sh" | "sh+wasm" => Ok(Self::ShPlusWasm),
+            "html" => Ok(Self::Html),
+            "pdf" | "pdf+wasm" | "pdf+html" => Ok(Self::PdfPlusWasm),
+            _ => Err(format!("Unknown target selection {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAGE_2: &str = "export default async function(input) {}";
+
+    /// A module with a type section, custom sections of its own and then those given.
+    fn module_with(trailing: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut module = wasm_encoder::Module::new();
+        // One function type without parameters and results.
+        module.section(&wasm_encoder::RawSection {
+            id: 1,
+            data: &[1, 0x60, 0, 0],
+        });
+        module.section(&wasm_encoder::CustomSection {
+            name: "name_of_its_own",
+            data: b"kept",
+        });
+
+        for &(name, data) in trailing {
+            module.section(&wasm_encoder::CustomSection { name, data });
+        }
+
+        module.finish()
+    }
+
+    fn zip() -> Vec<u8> {
+        use std::io::Write as _;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        zip.start_file("hello.txt", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"Hello, world").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn section_names(document: &[u8]) -> Vec<String> {
+        inspect::Document::new(document)
+            .unwrap()
+            .sections
+            .iter()
+            .filter_map(|section| section.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn repacking_is_idempotent() {
+        let targets = [
+            Target::WasmPlusHtml,
+            Target::Html,
+            Target::HtmlPlusZip,
+            Target::ShPlusWasm,
+        ];
+
+        for target in targets {
+            for zip_section in [None, Some("renamed_archive")] {
+                let mut packer = Packer::new(STAGE_2)
+                    .index_html("<p>Hello</p>")
+                    .section("wah_polyglot_extra", "extra")
+                    .trailing_zip(zip())
+                    .target(target);
+                if let Some(name) = zip_section {
+                    packer = packer.trailing_zip_section(name);
+                }
+
+                let once = packer.pack(&module_with(&[])).unwrap();
+                let twice = packer.pack(&once).unwrap();
+                assert!(once == twice, "repacking changed the {target} target");
+
+                // Without new inputs, the previous ones are retained as they were.
+                let retained = Packer::new(STAGE_2).target(target).pack(&once).unwrap();
+                assert!(
+                    once == retained,
+                    "repacking lost inputs of the {target} target"
+                );
+
+                let names = section_names(&twice);
+                assert_eq!(names[0], "wah_polyglot_stage0", "in {names:?}");
+
+                let mut unique = names.clone();
+                unique.sort();
+                unique.dedup();
+                assert_eq!(unique.len(), names.len(), "duplicates in {names:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn zip_of_a_plain_module_is_no_archive() {
+        let module = module_with(&[("assets", &zip())]);
+
+        let packed = Packer::new(STAGE_2).pack(&module).unwrap();
+        let document = inspect::Document::new(&packed).unwrap();
+        assert!(document.archive().is_none());
+
+        let assets = document
+            .sections
+            .iter()
+            .find(|section| section.name.as_deref() == Some("assets"))
+            .expect("the section is kept");
+        assert_eq!(&document.module[assets.range.clone()], zip().as_slice());
+        assert_eq!(unpack::unpack(&document).module, module);
+    }
+
+    #[test]
+    fn stage_1_loaders_share_verification() {
+        for template in [include_str!("stage1.js"), include_str!("stage1-edit.js")] {
+            let stage1 = with_verify(template);
+            assert!(!stage1.contains(VERIFY_PLACEHOLDER));
+            assert_eq!(stage1.matches("async function verify(").count(), 1);
+            assert_eq!(stage1.matches("function show_signer(").count(), 1);
+        }
+    }
+}
@@ -1,212 +1,194 @@
-use std::{io::Read, io::Write, path::PathBuf};
+use std::{
+    io::Read,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use wasm_as_html::{
+    check,
+    error::InvalidModuleError,
+    inspect,
+    manifest::{Manifest, Profile},
+    sign, unpack, Compression, Encoding, Target,
+};
+
+/// The manifest to use when only a profile is selected.
+const DEFAULT_MANIFEST: &str = "wah.toml";
+
+/// Exit code when the input is not a valid WebAssembly module, `EX_DATAERR` of `sysexits.h`.
+const EXIT_INVALID_MODULE: u8 = 65;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        None => pack(cli.pack).map(|()| ExitCode::SUCCESS),
+        Some(Command::Inspect(args)) => inspect(args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Unpack(args)) => unpack(args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Check(args)) => check(args),
+        Some(Command::Verify(args)) => verify(args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Run(args)) => run(args),
+    };
+
+    let err = match result {
+        Ok(code) => return code,
+        Err(err) => err,
+    };
+
+    eprintln!("Error: {err}");
+
+    if err.is::<InvalidModuleError>() {
+        ExitCode::from(EXIT_INVALID_MODULE)
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn inspect(args: InspectArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let data = read_input(args.file.as_deref())?;
+    let document =
+        inspect::Document::new(&data).map_err(|err| name_input(err, args.file.as_deref()))?;
 
-use clap::Parser;
-#[cfg(feature = "target-html+tar")]
-mod dom;
-mod error;
+    let stdout = std::io::stdout();
+    document.report(&mut stdout.lock())?;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    Ok(())
+}
+
+fn unpack(args: UnpackArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let data = read_input(args.file.as_deref())?;
+    let document =
+        inspect::Document::new(&data).map_err(|err| name_input(err, args.file.as_deref()))?;
+
+    if document.is_encrypted() {
+        Err("the document is encrypted, there is no module to recover without the password")?;
+    }
+
+    let unpacked = unpack::unpack(&document);
+
+    if let Some(dir) = &args.dir {
+        unpacked.write_to_dir(dir)?;
+    }
 
-    let stage_2 = std::fs::read(&args.stage_2)?;
-    let wasm = match &args.wasm {
+    match &args.out {
         None => {
-            let mut stdin = std::io::stdin();
-            let mut data = vec![];
-            stdin.read_to_end(&mut data)?;
-            data
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&unpacked.module)?;
+        }
+        Some(path) => {
+            std::fs::write(path, &unpacked.module)?;
         }
-        Some(path) => std::fs::read(path)?,
+    }
+
+    Ok(())
+}
+
+fn check(args: CheckArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let data = read_input(args.file.as_deref())?;
+    let report = check::check(&data);
+
+    let stdout = std::io::stdout();
+    report.report(&mut stdout.lock())?;
+
+    Ok(if report.passed() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn verify(args: VerifyArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let data = read_input(args.file.as_deref())?;
+    let document =
+        inspect::Document::new(&data).map_err(|err| name_input(err, args.file.as_deref()))?;
+
+    let expected = args.expect.as_deref().map(sign::expected_key).transpose()?;
+    let signer = match sign::verify(&document.module, expected.as_ref())? {
+        Some(signer) => signer,
+        None if document.is_encrypted() => {
+            Err("the document is encrypted, its signature is only readable with the password")?
+        }
+        None => Err("the document is not signed")?,
     };
+    println!("signed by {signer}");
+
+    Ok(())
+}
 
-    let parser = wasmparser::Parser::default();
-    let mut encoder = wasm_encoder::Module::new();
-
-    encoder.section(&wasm_encoder::CustomSection {
-        name: "wah_polyglot_stage0",
-        // Html designed to terminate processing into further WASM sections. This is the only
-        // section that needs to be placed specifically at the start. All other sections are then
-        // parsed from the module.
-        data: include_bytes!("stage0-wasm.html"),
-    });
-
-    // The actual (document) loader that prepares inputs and control for stage 2.
-    encoder.section(&wasm_encoder::CustomSection {
-        name: "wah_polyglot_stage1",
-        data: if args.edit {
-            assert!(std::env::var_os("WAH_POLYGLOT_EXPERIMENTAL").is_some());
-            include_bytes!("stage1-edit.js")
-        } else {
-            include_bytes!("stage1.js")
-        },
-    });
-
-    if let Some(index) = &args.index_html {
-        let index_html = std::fs::read(index)?;
-
-        encoder.section(&wasm_encoder::CustomSection {
-            name: "wah_polyglot_stage1_html",
-            data: &index_html,
-        });
+#[cfg(feature = "run")]
+fn run(args: RunArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let data = std::fs::read(&args.file)?;
+    let document =
+        inspect::Document::new(&data).map_err(|err| name_input(err, Some(args.file.as_path())))?;
+
+    let mut runner = wasm_as_html::run::Runner::default().args(args.args);
+    if let Some(root) = args.root {
+        runner = runner.root(root);
     }
 
-    encoder.section(&wasm_encoder::CustomSection {
-        name: "wah_polyglot_stage2",
-        data: &stage_2,
-    });
-
-    for section in parser.parse_all(&wasm) {
-        if let Some((id, data_range)) = section.map_err(parse_err)?.as_section() {
-            encoder.section(&wasm_encoder::RawSection {
-                id,
-                data: &wasm[data_range],
-            });
-        }
+    // Truncated to a byte, a failure such as 256 would read as success.
+    let code = runner.run(&document)?;
+    Ok(ExitCode::from(u8::try_from(code).unwrap_or(1)))
+}
+
+#[cfg(not(feature = "run"))]
+fn run(_: RunArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    Err(wasm_as_html::error::UnsupportedFeatureError {
+        what_to_use: "the `run` command".into(),
+        feature: "run".into(),
+    })?
+}
+
+fn pack(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = match (&args.manifest, &args.profile) {
+        (Some(path), _) => Some(Manifest::from_file(path)?),
+        (None, Some(_)) => Some(Manifest::from_file(Path::new(DEFAULT_MANIFEST))?),
+        (None, None) => None,
+    };
+
+    if manifest.is_some() && args.stage_2.is_some() && args.wasm.is_none() {
+        return Err(
+            "with a manifest, a single positional argument is ambiguous. Give the stage 2 loader \
+             with `--stage2` or the module with `--module` instead"
+                .into(),
+        );
     }
 
-    for extra in &args.extra_section {
-        encoder.section(&wasm_encoder::CustomSection {
-            name: &extra.name,
-            data: &std::fs::read(&extra.from_file)?,
-        });
+    let mut profile = match &manifest {
+        Some(manifest) => manifest.profile(args.profile.as_deref())?,
+        None => Profile::default(),
+    };
+
+    profile.merge(args.to_profile());
+
+    let wasm = read_input(profile.module.as_deref())?;
+
+    if args.edit {
+        assert!(std::env::var_os("WAH_POLYGLOT_EXPERIMENTAL").is_some());
     }
 
-    if let Some(zip_file) = &args.zip {
-        let zip_data = std::fs::read(zip_file)?;
-        let name = args
-            .zip_section_name
-            .as_deref()
-            .unwrap_or("wah_polyglot_stage2_data");
-
-        encoder.section(&wasm_encoder::CustomSection {
-            name,
-            data: &zip_data,
-        });
+    let packer = profile.packer()?.experimental_edit(args.edit);
+
+    let wasm = packer
+        .pack(&wasm)
+        .map_err(|err| name_input(err, profile.module.as_deref()))?;
+
+    if profile.compress.is_some() || profile.encoding.is_some() {
+        report_encoding(&wasm)?;
     }
 
-    let wasm = match args.target {
-        Target::WasmPlusHtml => encoder.finish(),
-        Target::Html => {
-            use base64::{display::Base64Display, engine::general_purpose};
-            let wasm = encoder.finish();
-            let template = include_str!("stage0-html.html");
-
-            // To include our WebAssembly module as data, we need to massage the data into an HTML
-            // compatible form. In the end, access to it as an ArrayBuffer is required. The pure
-            // `fetch` is sometimes limited by the browser so maybe that's a problem? But it is the
-            // most efficient base64 decoder we have. And it is **correct**.
-            //
-            // <https://stackoverflow.com/questions/21797299/convert-base64-string-to-arraybuffer>
-            // There answers are mostly bad, and confidently incorrect.
-            let wasm = Base64Display::new(&wasm, &general_purpose::STANDARD);
-            let data_uri = format!("data:application/octet-stream;base64,{wasm}");
-            let data_uri_constructor = format!("{data_uri}");
-            let with_data = template.replace(
-                "__REPLACE_THIS_WITH_WASM_AS_A_DATA_URI__",
-                &data_uri_constructor,
-            );
-
-            // 16 MB is generally okay..
-            let loaded = if data_uri.len().ilog2() < 24 {
-                // Nothing to do..
-                with_data.replace(
-                    "__REPLACE_THIS_WITH_URI_LOADER__",
-                    "await (async function() {
-                    let doc = await fetch(URI_SRC);
-                    return await doc.arrayBuffer();
-                })()",
-                )
-            } else if data_uri.len().ilog2() < 31 {
-                // For larger module (scarily large) we need a different strategy that is not yet
-                // implemented here. In particular, Firefox makes a 32MB restriction on the size of
-                // a DataUrl which seems to be enforced when `fetch` is called (which converts it
-                // into an URL object? Not quite sure where the actual restriction is placed).
-                // We do
-                //
-                // FIXME: Evaluate ReadableByteStream for chunk-based yields <https://developer.mozilla.org/en-US/docs/Web/API/ReadableByteStreamController>
-                //
-                with_data.replace(
-                    "__REPLACE_THIS_WITH_URI_LOADER__",
-                    include_str!("stage-snippet-load-URI-b64.js"),
-                )
-            } else {
-                // This is too large for most String implementations..
-                panic!("The `html` target does not support modules larger than 2GB.");
-            };
-
-            loaded.into()
-        }
-        #[cfg(not(feature = "target-html+tar"))]
-        Target::HtmlPlusTar => {
-            return Err(error::UnsupportedFeatureError {
-                what_to_use: "target-html+tar".into(),
-                feature: "target-html+tar".into(),
-            })?;
-        }
-        #[cfg(feature = "target-html+tar")]
-        Target::HtmlPlusTar => {
-            let Some(template) = args.index_html else {
-                panic!("The `html+tar` target embeds into the index HTML.");
-            };
-
-            let source = std::fs::read_to_string(&template)?;
-            let mut source = dom::SourceDocument::new(&source);
-            let binary_wasm = encoder.finish();
-            let source_script = include_bytes!("stage0-html_plus_tar.js");
-
-            let structure = source.prepare_tar_structure()?;
-
-            let mut engine = html_and_tar::TarEngine::default();
-            let mut seq_of_bytes: Vec<&[u8]> = vec![];
-
-            let mut head_span = source.span(structure.html_tag);
-            head_span.end = head_span.start + structure.html_insertion_point;
-            head_span.start = 0;
-
-            let head = &source[head_span];
-            let where_to_insert = source.span(structure.insertion_tag);
-            let where_to_enter = source.span(structure.stage0);
-
-            assert!(where_to_insert.end < where_to_enter.start);
-
-            let init = engine.start_of_file(head.as_bytes(), where_to_insert.start);
-            seq_of_bytes.push(init.header.as_bytes());
-            seq_of_bytes.push(init.extra.as_slice());
-            seq_of_bytes.push(source[init.consumed..where_to_insert.start].as_bytes());
-
-            let mut pushed_data = vec![];
-
-            pushed_data.push(engine.escaped_insert_base64(html_and_tar::Entry {
-                name: "boot/wah-init.wasm",
-                data: &binary_wasm,
-            }));
-
-            for data in &pushed_data {
-                seq_of_bytes.push(data.padding);
-                seq_of_bytes.push(data.header.as_bytes());
-                seq_of_bytes.push(data.file.as_bytes());
-                seq_of_bytes.push(data.data.as_slice());
-            }
-
-            // FIXME: not sure if we should just do the open-end thing instead of EOF..
-
-            let eof = engine.escaped_eof();
-            seq_of_bytes.push(eof.padding);
-            seq_of_bytes.push(eof.header.as_bytes());
-            seq_of_bytes.push(eof.file.as_bytes());
-            seq_of_bytes.push(eof.data.as_slice());
-
-            seq_of_bytes.push(source[where_to_insert.end..where_to_enter.start].as_bytes());
-            seq_of_bytes.push(b"<script>");
-            seq_of_bytes.push(source_script);
-            seq_of_bytes.push(b"</script>");
-            seq_of_bytes.push(source[where_to_enter.end..].as_bytes());
-
-            seq_of_bytes.join(&b""[..])
-        }
-    };
+    if profile.password_file.is_some()
+        && inspect::Document::new(&wasm)?.target == Target::WasmPlusHtml
+    {
+        eprintln!(
+            "The encrypted document is a page only: no longer a WebAssembly module that runs by \
+             itself, nor a zip archive of its data"
+        );
+    }
 
-    match &args.out {
+    match &profile.out {
         None => {
             let mut stdout = std::io::stdout();
             stdout.write_all(&wasm)?;
@@ -219,8 +201,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn parse_err(_: wasmparser::BinaryReaderError) -> std::io::Error {
-    todo!()
+/// Compare the encoded module to the plain base64 it would have been otherwise.
+fn report_encoding(document: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let document = inspect::Document::new(document)?;
+    let plain = document.module.len().div_ceil(3) * 4;
+    let encoded = document.module_span.len();
+    let saved = plain.saturating_sub(encoded);
+
+    eprintln!(
+        "Encoded the module in {encoded} instead of {plain} bytes of plain base64, saving {saved} bytes ({:.1}%)",
+        saved as f64 * 100.0 / plain.max(1) as f64,
+    );
+
+    Ok(())
+}
+
+/// Read a file, or all of stdin if no path is given.
+fn read_input(path: Option<&Path>) -> Result<Vec<u8>, std::io::Error> {
+    match path {
+        None => {
+            let mut stdin = std::io::stdin();
+            let mut data = vec![];
+            stdin.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(path) => std::fs::read(path),
+    }
+}
+
+/// Attribute an invalid module to the input it was read from.
+fn name_input(err: Box<dyn std::error::Error>, path: Option<&Path>) -> Box<dyn std::error::Error> {
+    match err.downcast::<InvalidModuleError>() {
+        Ok(mut err) => {
+            err.file = Some(path.unwrap_or(Path::new("<stdin>")).to_owned());
+            err
+        }
+        Err(err) => err,
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -230,6 +247,92 @@ struct ExtraSection {
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    pack: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Report the polyglot structure of a packed file.
+    ///
+    /// Detects the target that produced the file and lists the sections of the embedded
+    /// WebAssembly module with their names, sizes and offsets.
+    Inspect(InspectArgs),
+    /// Recover the original WebAssembly module from a packed file.
+    ///
+    /// Strips all sections added by the packer, and undoes the encoding of the `html` and
+    /// `html+tar` targets.
+    Unpack(UnpackArgs),
+    /// Verify the polyglot invariants of a packed file.
+    ///
+    /// Checks that the module parses with stage 0 as its first section and a single stage 2
+    /// loader, that an HTML tokenizer reaches the stage 0 script before any binary data, and for
+    /// `html+tar` that a tar reader accepts every header. Exits with failure if any check fails.
+    Check(CheckArgs),
+    /// Verify the signature of a packed file.
+    ///
+    /// Checks the signature as the stage 1 loader does and prints the signer. Fails if the file
+    /// is not signed, was modified after signing, or is signed with another key than expected.
+    /// Without an expected key, the identity of the signer is only its claim.
+    Verify(VerifyArgs),
+    /// Boot a packed WASI document outside the browser.
+    ///
+    /// Builds the file system that the WASI stage 2 loader configures, runs stage 3 and then the
+    /// application with an embedded WASI runtime, and exits with the application's exit code.
+    Run(RunArgs),
+}
+
+#[derive(clap::Args)]
+struct InspectArgs {
+    /// The packed file to inspect, default stdin.
+    file: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct UnpackArgs {
+    /// The packed file to unpack, default stdin.
+    file: Option<PathBuf>,
+    /// A file to write the original module to, default stdout.
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+    /// A directory to write the stage 2 loader, index HTML, data archive and other added sections
+    /// to.
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// The packed file to check, default stdin.
+    file: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// The packed file to verify, default stdin.
+    file: Option<PathBuf>,
+    /// The key that must have signed, as a PEM file or the `ed25519:` fingerprint it shows.
+    #[arg(long, value_name = "KEY|FINGERPRINT")]
+    expect: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// The packed file to run.
+    file: PathBuf,
+    /// A directory to write the file system to and keep, instead of a temporary one.
+    #[arg(long)]
+    root: Option<PathBuf>,
+    /// Arguments to the application.
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+#[derive(clap::Args)]
 struct Args {
     // Positional arguments
     /// The stage 2 loader payload, a JS module.
@@ -242,8 +345,15 @@ struct Args {
     /// indicated `index.html`. The stage 1 will call its default export as
     ///
     /// stage2_module.default(Promise.resolve(new Response(wasmblob)))
-    #[arg(name = "STAGE2_JS")]
-    stage_2: PathBuf,
+    ///
+    /// Instead of a path, `builtin:wasi` selects the WASI loader and `builtin:bindgen-web` a loader
+    /// for wasm-bindgen glue, packed as the `wah_polyglot_wasm_bindgen` section.
+    /// `builtin:emscripten` runs Emscripten glue and `builtin:go` the `wasm_exec.js` of Go, either
+    /// of which may also be given as the path itself.
+    /// `builtin:auto` picks one from the imports of the module. A loader that does not match
+    /// those imports is refused.
+    #[arg(name = "STAGE2_JS", required_unless_present_any = ["manifest", "profile", "loader"])]
+    stage_2: Option<PathBuf>,
     /// The web assembly module to embed ourselves in, default stdin.
     wasm: Option<PathBuf>,
 
@@ -258,10 +368,18 @@ struct Args {
     /// A zip file to attach.
     ///
     /// This file is added as a final section of the module (so its central archive is within the
-    /// last 512 bytes).
+    /// last 512 bytes). Its offsets are rewritten such that the `wasm` output is also a valid zip
+    /// archive.
     #[arg(short, long = "trailing-zip", alias = "zip")]
     zip: Option<PathBuf>,
 
+    /// A directory to attach as a zip file, instead of `--trailing-zip`.
+    ///
+    /// The archive is built deterministically: entries are sorted by path and have fixed
+    /// timestamps and permissions, so the same files always produce the same document.
+    #[arg(long, conflicts_with = "zip")]
+    data_dir: Option<PathBuf>,
+
     #[arg(long = "add-section")]
     extra_section: Vec<ExtraSection>,
 
@@ -273,8 +391,8 @@ struct Args {
 
     /// How to wrap the output Web Assembly module.
     ///
-    /// This determines the 'stage 0' entry point into setting up the web assembly. There are two
-    /// options:
+    /// This determines the 'stage 0' entry point into setting up the web assembly. The options
+    /// are:
     ///
     /// * `wasm`, which enters execution from an initial section that looks like valid HTML. This
     ///   target is NOT compatible with serving from a file in Chromium, as it requires access to
@@ -282,8 +400,96 @@ struct Args {
     /// * `html`, which encodes the resulting module as a blob and loads it. This target is
     ///   generally compatible with web browsers but obviously the output file is no longer a
     ///   WebAssembly module itself.
-    #[arg(long, short = 't', alias = "target", default_value = "wasm")]
-    target: Target,
+    /// * `html+tar`, which embeds the module into the index HTML such that the file is also a tar
+    ///   archive.
+    /// * `html+zip`, like `html` but the file is also a zip archive of the files in the data
+    ///   archive, for anyone who would rather extract them with the tools of their desktop.
+    /// * `epub`, which packages the module into an EPUB publication. Its chapter is the index HTML
+    ///   converted to XHTML, which loads the module where the reader allows scripting.
+    /// * `sh`, like `wasm` but preceded by a shell script. Executed, the file runs the module with
+    ///   `wasmtime` or `wasmer` and the data archive mounted as its root directory.
+    /// * `pdf`, like `wasm` but the file also opens as the document given with `--pdf` in a PDF
+    ///   viewer.
+    ///
+    /// Defaults to `wasm`.
+    #[arg(long, short = 't', alias = "target")]
+    target: Option<Target>,
+
+    /// Compress the module for the `html` and `html+zip` targets, with `gzip` or `deflate`.
+    ///
+    /// The stage 0 loader inflates the module with the browser's `DecompressionStream`. Reports
+    /// the number of bytes saved.
+    #[arg(long)]
+    compress: Option<Compression>,
+
+    /// How to encode the module as text for the `html`, `html+tar` and `html+zip` targets.
+    ///
+    /// * `base64`, the default, adds a third to the size of the module.
+    /// * `base122` adds about a seventh. It requires the document to be read as UTF-8, so the
+    ///   index HTML of `html+tar` must declare `<meta charset="utf-8">`.
+    #[arg(long)]
+    encoding: Option<Encoding>,
+
+    /// A PDF document for the `pdf` target.
+    ///
+    /// It must use a classic cross-reference table without incremental updates, re-save it with
+    /// `qpdf --object-streams=disable` otherwise.
+    #[arg(long)]
+    pdf: Option<PathBuf>,
+
+    /// Sign the document with an ed25519 private key, in PEM form.
+    ///
+    /// Create one with `openssl genpkey -algorithm ed25519 -out key.pem`. The signature covers the
+    /// module, the stage 2 loader, the index HTML and the data archive. The stage 1 loader
+    /// verifies it before running stage 2 and shows the signer, `verify` does so natively.
+    #[arg(long)]
+    sign_key: Option<PathBuf>,
+
+    /// Who signs the document, such as a name and email address, shown next to the key.
+    #[arg(long, requires = "sign_key")]
+    signer: Option<String>,
+
+    /// Encrypt the document with the password in the first line of a file.
+    ///
+    /// Stage 0 asks for the password and decrypts the module with WebCrypto, AES-256-GCM under a
+    /// key derived with PBKDF2. Only the `wasm`, `html` and `html+tar` targets can be encrypted.
+    /// A `wasm` document is no longer a module that runs by itself, nor a zip of its data.
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// Fully validate the module before packing.
+    ///
+    /// Rejects modules that would fail to compile in the stage 0 loader, instead of producing a
+    /// broken document. Only checks that the module can be parsed otherwise.
+    #[arg(long)]
+    validate: bool,
+
+    /// A manifest declaring the inputs and options, see `wah.toml`.
+    ///
+    /// All other arguments override the values of the manifest.
+    #[arg(long, short = 'm')]
+    manifest: Option<PathBuf>,
+
+    /// The profile of the manifest to pack.
+    ///
+    /// Reads the manifest from `wah.toml` if none is given.
+    #[arg(long, short = 'p')]
+    profile: Option<String>,
+
+    /// The stage 2 loader, in place of `STAGE2_JS`.
+    ///
+    /// With a manifest, a single positional argument could name either input, so override just
+    /// the loader with this.
+    #[arg(
+        long = "stage2",
+        value_name = "STAGE2_JS",
+        conflicts_with = "STAGE2_JS"
+    )]
+    loader: Option<PathBuf>,
+
+    /// The web assembly module, in place of the positional argument.
+    #[arg(long, conflicts_with = "wasm")]
+    module: Option<PathBuf>,
 
     // Experimental section.
     /// Experimental. Hot-reload when the WASM file changes.
@@ -297,24 +503,34 @@ struct Args {
     edit: bool,
 }
 
-#[derive(Clone)]
-enum Target {
-    WasmPlusHtml,
-    Html,
-    HtmlPlusTar,
-}
-
-impl core::str::FromStr for Target {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "wasm" => Ok(Self::WasmPlusHtml),
-            "wasm+html" => Ok(Self::WasmPlusHtml),
-            "html+tar" => Ok(Self::HtmlPlusTar),
-            "html" => Ok(Self::Html),
-            _ => Err(format!("Unknown target selection {s}")),
-        }
+impl Args {
+    /// The options given on the command line, as a profile overriding any manifest.
+    fn to_profile(&self) -> Profile {
+        let mut profile = Profile::default();
+
+        profile.target = self.target.map(|target| target.to_string());
+        profile.compress = self.compress.map(|compression| compression.to_string());
+        profile.encoding = self.encoding.map(|encoding| encoding.to_string());
+        profile.module = self.wasm.clone().or_else(|| self.module.clone());
+        profile.stage2 = self.stage_2.clone().or_else(|| self.loader.clone());
+        profile.index_html = self.index_html.clone();
+        profile.data = self.zip.clone();
+        profile.data_dir = self.data_dir.clone();
+        profile.data_section = self.zip_section_name.clone();
+        profile.pdf = self.pdf.clone();
+        profile.sign_key = self.sign_key.clone();
+        profile.signer = self.signer.clone();
+        profile.password_file = self.password_file.clone();
+        profile.out = self.out.clone();
+        profile.validate = self.validate.then_some(true);
+        profile.sections = self
+            .extra_section
+            .iter()
+            .map(|extra| (extra.name.clone(), extra.from_file.clone()))
+            .collect();
+
+        profile.locate(|key| format!("command line `{key}`"), Path::new(""));
+        profile
     }
 }
 
@@ -0,0 +1,559 @@
+//! The declarative pack manifest, usually `wah.toml`.
+//!
+//! A manifest describes everything that goes into a document. Top-level keys in the `[pack]`
+//! table apply to all profiles while each `[profile.<name>]` table overrides them:
+//!
+//! ```toml
+//! [pack]
+//! target = "html"
+//! compress = "gzip"
+//! stage2 = "wasi-loader/out.js"
+//! stage3 = "target/wasm32-wasi/release/unzip.wasm"
+//! data-dir = "assets"
+//!
+//! [profile.todomvc]
+//! module = "examples/yew/yew/target/generated/todomvc_bg.wasm"
+//! index-html = "examples/yew/yew/examples/todomvc/index.html"
+//! out = "todomvc.html"
+//!
+//! [profile.todomvc.sections]
+//! wah_polyglot_wasm_bindgen = "examples/yew/yew/target/generated/todomvc.js"
+//! ```
+//!
+//! Relative paths are resolved from the directory containing the manifest. The command line
+//! arguments of the packer fill a [`Profile`] in the same way, overriding the manifest.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{error::ManifestError, stage2::Builtin, Compression, Encoding, Packer, Target};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Manifest {
+    /// The defaults for all profiles.
+    #[serde(default)]
+    pub pack: Profile,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+    /// The file this manifest was read from.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// All inputs and options of one packed document.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    /// How to wrap the output module, see [`Target`].
+    pub target: Option<String>,
+    /// Compress the module for the `html` target, see [`Compression`].
+    pub compress: Option<String>,
+    /// How to encode the module as text for the `html` and `html+tar` targets, see [`Encoding`].
+    pub encoding: Option<String>,
+    /// The WebAssembly module to pack.
+    pub module: Option<PathBuf>,
+    /// The stage 2 loader payload, a JS module or a [`Builtin`]. Picked for the module if absent.
+    pub stage2: Option<PathBuf>,
+    /// An HTML page to use when invoking the loader.
+    pub index_html: Option<PathBuf>,
+    /// The stage 3 module, added as the `wah_polyglot_stage3` section.
+    pub stage3: Option<PathBuf>,
+    /// Additional custom sections, by name and in order.
+    #[serde(default, deserialize_with = "sections_in_order")]
+    pub sections: Vec<(String, PathBuf)>,
+    /// A zip file to attach as the data archive.
+    pub data: Option<PathBuf>,
+    /// A directory to build the data archive from, instead of `data`.
+    pub data_dir: Option<PathBuf>,
+    /// A customized section name for the data archive.
+    pub data_section: Option<String>,
+    /// A PDF document for the `pdf` target.
+    pub pdf: Option<PathBuf>,
+    /// An ed25519 private key in PEM form to sign the document with.
+    pub sign_key: Option<PathBuf>,
+    /// Who signs the document, shown next to the key.
+    pub signer: Option<String>,
+    /// A file whose first line is the password to encrypt the document with.
+    pub password_file: Option<PathBuf>,
+    /// Where to write the document.
+    pub out: Option<PathBuf>,
+    /// Fully validate the module before packing.
+    pub validate: Option<bool>,
+    /// Where each key was defined, for diagnostics.
+    #[serde(skip)]
+    origin: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Self, ManifestError> {
+        let text = std::fs::read_to_string(path).map_err(|err| ManifestError {
+            key: path.display().to_string(),
+            message: err.to_string(),
+        })?;
+
+        let mut manifest: Manifest = toml::from_str(&text).map_err(|err| ManifestError {
+            key: path.display().to_string(),
+            message: err.to_string(),
+        })?;
+
+        manifest.path = path.to_owned();
+        Ok(manifest)
+    }
+
+    /// The defaults merged with the named profile, with paths resolved relative to the manifest.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ManifestError> {
+        let root = self.path.parent().unwrap_or(Path::new(""));
+        let manifest = self.path.display();
+
+        let mut profile = self.pack.clone();
+        profile.locate(|key| format!("{manifest}: pack.{key}"), root);
+
+        if let Some(name) = name {
+            let mut named = self
+                .profile
+                .get(name)
+                .ok_or_else(|| ManifestError {
+                    key: format!("{manifest}: profile.{name}"),
+                    message: "no such profile".into(),
+                })?
+                .clone();
+
+            named.locate(|key| format!("{manifest}: profile.{name}.{key}"), root);
+            profile.merge(named);
+        }
+
+        Ok(profile)
+    }
+}
+
+impl Profile {
+    /// Record where each of the keys present was defined, and resolve paths from `root`.
+    pub fn locate(&mut self, origin: impl Fn(&str) -> String, root: &Path) {
+        // A builtin stage 2 loader is no path.
+        let stage2 = self
+            .stage2
+            .as_mut()
+            .filter(|path| Builtin::from_path(path).is_none());
+
+        for path in [
+            &mut self.module,
+            &mut self.index_html,
+            &mut self.stage3,
+            &mut self.data,
+            &mut self.data_dir,
+            &mut self.pdf,
+            &mut self.sign_key,
+            &mut self.password_file,
+            &mut self.out,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(stage2)
+        .chain(self.sections.iter_mut().map(|(_, path)| path))
+        {
+            *path = root.join(&*path);
+        }
+
+        let keys = [
+            ("target", self.target.is_some()),
+            ("compress", self.compress.is_some()),
+            ("encoding", self.encoding.is_some()),
+            ("module", self.module.is_some()),
+            ("stage2", self.stage2.is_some()),
+            ("index-html", self.index_html.is_some()),
+            ("stage3", self.stage3.is_some()),
+            ("data", self.data.is_some()),
+            ("data-dir", self.data_dir.is_some()),
+            ("data-section", self.data_section.is_some()),
+            ("pdf", self.pdf.is_some()),
+            ("sign-key", self.sign_key.is_some()),
+            ("signer", self.signer.is_some()),
+            ("password-file", self.password_file.is_some()),
+            ("out", self.out.is_some()),
+            ("validate", self.validate.is_some()),
+        ];
+
+        let present = keys
+            .into_iter()
+            .filter(|&(_, is_some)| is_some)
+            .map(|(key, _)| key.to_string())
+            .chain(
+                self.sections
+                    .iter()
+                    .map(|(name, _)| format!("sections.{name}")),
+            );
+
+        for key in present.collect::<Vec<_>>() {
+            let at = origin(&key);
+            self.origin.insert(key, at);
+        }
+    }
+
+    /// Override all keys that are present in `other`.
+    pub fn merge(&mut self, other: Profile) {
+        let Profile {
+            target,
+            compress,
+            encoding,
+            module,
+            stage2,
+            index_html,
+            stage3,
+            sections,
+            data,
+            data_dir,
+            data_section,
+            pdf,
+            sign_key,
+            signer,
+            password_file,
+            out,
+            validate,
+            origin,
+        } = other;
+
+        self.target = target.or(self.target.take());
+        self.compress = compress.or(self.compress.take());
+        self.encoding = encoding.or(self.encoding.take());
+        self.module = module.or(self.module.take());
+        self.stage2 = stage2.or(self.stage2.take());
+        self.index_html = index_html.or(self.index_html.take());
+        self.stage3 = stage3.or(self.stage3.take());
+
+        // Sections replace those of the same name, but may themselves repeat a name.
+        let existing = self.sections.len();
+        for (name, path) in sections {
+            let slot = self.sections[..existing]
+                .iter_mut()
+                .find(|(previous, _)| *previous == name);
+
+            match slot {
+                Some(slot) => slot.1 = path,
+                None => self.sections.push((name, path)),
+            }
+        }
+
+        // Either source of the data archive overrides the other.
+        if data.is_some() || data_dir.is_some() {
+            self.data = data;
+            self.data_dir = data_dir;
+        }
+
+        self.data_section = data_section.or(self.data_section.take());
+        self.pdf = pdf.or(self.pdf.take());
+        self.sign_key = sign_key.or(self.sign_key.take());
+        self.signer = signer.or(self.signer.take());
+        self.password_file = password_file.or(self.password_file.take());
+        self.out = out.or(self.out.take());
+        self.validate = validate.or(self.validate.take());
+        self.origin.extend(origin);
+    }
+
+    /// Read all inputs into a packer.
+    pub fn packer(&self) -> Result<Packer, ManifestError> {
+        // Without a stage 2 loader, the packer picks one for the module.
+        let stage2 = self
+            .stage2
+            .as_deref()
+            .map(|path| (path, Builtin::from_path(path)));
+
+        let mut packer = match stage2 {
+            None | Some((_, Some(Ok(Builtin::Auto)))) => Packer::automatic(),
+            Some((path, None)) => Packer::new(self.read("stage2", path)?),
+            Some((_, Some(builtin))) => {
+                let source = builtin
+                    .map_err(Box::<dyn core::error::Error>::from)
+                    .and_then(Builtin::source)
+                    .map_err(|err| ManifestError {
+                        key: self.key("stage2"),
+                        message: err.to_string(),
+                    })?;
+
+                Packer::new(source)
+            }
+        };
+
+        if let Some(target) = &self.target {
+            let target = target.parse::<Target>().map_err(|message| ManifestError {
+                key: self.key("target"),
+                message,
+            })?;
+
+            packer = packer.target(target);
+        }
+
+        if let Some(compress) = &self.compress {
+            let compression = compress
+                .parse::<Compression>()
+                .map_err(|message| ManifestError {
+                    key: self.key("compress"),
+                    message,
+                })?;
+
+            packer = packer.compress(Some(compression));
+        }
+
+        if let Some(encoding) = &self.encoding {
+            let encoding = encoding
+                .parse::<Encoding>()
+                .map_err(|message| ManifestError {
+                    key: self.key("encoding"),
+                    message,
+                })?;
+
+            packer = packer.encoding(encoding);
+        }
+
+        if let Some(index_html) = &self.index_html {
+            packer = packer.index_html(self.read("index-html", index_html)?);
+        }
+
+        if let Some(stage3) = &self.stage3 {
+            packer = packer.section("wah_polyglot_stage3", self.read("stage3", stage3)?);
+        }
+
+        for (name, path) in &self.sections {
+            packer = packer.section(name, self.read(&format!("sections.{name}"), path)?);
+        }
+
+        match (&self.data, &self.data_dir) {
+            (Some(_), Some(_)) => {
+                return Err(ManifestError {
+                    key: self.key("data-dir"),
+                    message: format!("conflicts with `{}`", self.key("data")),
+                })
+            }
+            (Some(data), None) => {
+                packer = packer.trailing_zip(self.read("data", data)?);
+            }
+            (None, Some(dir)) => {
+                let archive = crate::archive::from_dir(dir).map_err(|err| ManifestError {
+                    key: self.key("data-dir"),
+                    message: format!("could not build the archive: {err}"),
+                })?;
+
+                packer = packer.trailing_zip(archive);
+            }
+            (None, None) => {}
+        }
+
+        if let Some(name) = &self.data_section {
+            packer = packer.trailing_zip_section(name);
+        }
+
+        if let Some(pdf) = &self.pdf {
+            packer = packer.pdf(self.read("pdf", pdf)?);
+        }
+
+        match (&self.sign_key, &self.signer) {
+            (Some(key), signer) => {
+                packer = packer.signing_key(self.read("sign-key", key)?);
+                if let Some(signer) = signer {
+                    packer = packer.signer(signer);
+                }
+            }
+            (None, Some(_)) => {
+                return Err(ManifestError {
+                    key: self.key("signer"),
+                    message: "requires a `sign-key` to sign with".into(),
+                })
+            }
+            (None, None) => {}
+        }
+
+        if let Some(path) = &self.password_file {
+            let contents = self.read("password-file", path)?;
+            let password = String::from_utf8(contents).map_err(|_| ManifestError {
+                key: self.key("password-file"),
+                message: "the password is not UTF-8".into(),
+            })?;
+
+            let password = password.lines().next().unwrap_or_default();
+            packer = packer.password(password);
+        }
+
+        Ok(packer.validate(self.validate.unwrap_or(false)))
+    }
+
+    /// Describe where a key was defined.
+    pub fn key(&self, key: &str) -> String {
+        self.origin
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    fn read(&self, key: &str, path: &Path) -> Result<Vec<u8>, ManifestError> {
+        std::fs::read(path).map_err(|err| ManifestError {
+            key: self.key(key),
+            message: format!("could not read `{}`: {err}", path.display()),
+        })
+    }
+}
+
+/// Collect a table of sections, retaining the order in which they are written.
+fn sections_in_order<'de, D>(deserializer: D) -> Result<Vec<(String, PathBuf)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct InOrder;
+
+    impl<'de> serde::de::Visitor<'de> for InOrder {
+        type Value = Vec<(String, PathBuf)>;
+
+        fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.write_str("a table of section names to files")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            let mut sections = vec![];
+
+            while let Some(entry) = map.next_entry()? {
+                sections.push(entry);
+            }
+
+            Ok(sections)
+        }
+    }
+
+    deserializer.deserialize_map(InOrder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[pack]
+target = "html"
+stage2 = "builtin:wasi"
+data = "data.zip"
+out = "/srv/www/index.html"
+
+[pack.sections]
+zeta = "zeta.bin"
+alpha = "alpha.bin"
+
+[profile.app]
+target = "wasm"
+module = "target/app.wasm"
+data-dir = "assets"
+
+[profile.app.sections]
+alpha = "app/alpha.bin"
+mu = "mu.bin"
+"#;
+
+    fn manifest(path: &str, text: &str) -> Manifest {
+        let mut manifest: Manifest = toml::from_str(text).unwrap();
+        manifest.path = PathBuf::from(path);
+        manifest
+    }
+
+    fn sections(profile: &Profile) -> Vec<(&str, &Path)> {
+        profile
+            .sections
+            .iter()
+            .map(|(name, path)| (name.as_str(), path.as_path()))
+            .collect()
+    }
+
+    #[test]
+    fn sections_keep_their_order() {
+        let manifest = manifest("wah.toml", MANIFEST);
+
+        let names: Vec<_> = manifest
+            .pack
+            .sections
+            .iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["zeta", "alpha"]);
+    }
+
+    #[test]
+    fn profile_merges_over_the_base() {
+        let manifest = manifest("wah.toml", MANIFEST);
+        let profile = manifest.profile(Some("app")).unwrap();
+
+        assert_eq!(profile.target.as_deref(), Some("wasm"));
+        assert_eq!(profile.stage2.as_deref(), Some(Path::new("builtin:wasi")));
+        assert_eq!(
+            profile.module.as_deref(),
+            Some(Path::new("target/app.wasm"))
+        );
+        // The directory replaces the zip of the base, rather than conflicting with it.
+        assert_eq!(profile.data, None);
+        assert_eq!(profile.data_dir.as_deref(), Some(Path::new("assets")));
+        // Replaced in place, added at the end.
+        assert_eq!(
+            sections(&profile),
+            [
+                ("zeta", Path::new("zeta.bin")),
+                ("alpha", Path::new("app/alpha.bin")),
+                ("mu", Path::new("mu.bin")),
+            ]
+        );
+
+        assert_eq!(profile.key("target"), "wah.toml: profile.app.target");
+        assert_eq!(profile.key("stage2"), "wah.toml: pack.stage2");
+        assert_eq!(profile.key("sections.zeta"), "wah.toml: pack.sections.zeta");
+        assert_eq!(
+            profile.key("sections.mu"),
+            "wah.toml: profile.app.sections.mu"
+        );
+
+        let base = manifest.profile(None).unwrap();
+        assert_eq!(base.target.as_deref(), Some("html"));
+        assert_eq!(base.data.as_deref(), Some(Path::new("data.zip")));
+
+        let err = manifest.profile(Some("missing")).err().unwrap();
+        assert_eq!(err.key, "wah.toml: profile.missing");
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_manifest() {
+        let manifest = manifest("site/wah.toml", MANIFEST);
+        let profile = manifest.profile(Some("app")).unwrap();
+
+        assert_eq!(
+            profile.module.as_deref(),
+            Some(Path::new("site/target/app.wasm"))
+        );
+        assert_eq!(profile.data_dir.as_deref(), Some(Path::new("site/assets")));
+        assert_eq!(sections(&profile)[0], ("zeta", Path::new("site/zeta.bin")));
+        // Neither builtin loaders nor absolute paths are relative.
+        assert_eq!(profile.stage2.as_deref(), Some(Path::new("builtin:wasi")));
+        assert_eq!(
+            profile.out.as_deref(),
+            Some(Path::new("/srv/www/index.html"))
+        );
+    }
+
+    #[test]
+    fn locate_records_where_keys_are_defined() {
+        let mut profile = Profile {
+            module: Some("app.wasm".into()),
+            sections: vec![("extra".into(), "extra.bin".into())],
+            ..Profile::default()
+        };
+
+        profile.locate(|key| format!("command line `{key}`"), Path::new("root"));
+
+        assert_eq!(profile.module.as_deref(), Some(Path::new("root/app.wasm")));
+        assert_eq!(profile.key("module"), "command line `module`");
+        assert_eq!(
+            profile.key("sections.extra"),
+            "command line `sections.extra`"
+        );
+        // Keys that are absent are named as they are.
+        assert_eq!(profile.key("out"), "out");
+    }
+}
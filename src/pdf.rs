@@ -0,0 +1,283 @@
+//! Places a PDF document into the last section of a module.
+//!
+//! PDF readers look for the `%PDF-` header within the first kilobyte of a file, which the stage 0
+//! section provides, and then read the document from its end: `startxref` names the offset of the
+//! cross-reference table, which in turn names the offset of every object. With the original PDF
+//! embedded in a custom section, all of these offsets must count from the start of the packed file
+//! instead. Everything in between is never looked at.
+//!
+//! Only classic cross-reference tables with fixed-width entries can be rewritten in place. PDFs
+//! with cross-reference streams or incremental updates should be re-saved first, for instance
+//! with `qpdf --object-streams=disable`.
+use core::ops::Range;
+
+use crate::error::InvalidPdfError;
+
+/// The header written into stage 0, the second line marks the file as binary.
+pub const HEADER: &[u8] = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n";
+
+const STARTXREF: &[u8] = b"startxref";
+const XREF: &[u8] = b"xref";
+const TRAILER: &[u8] = b"trailer";
+
+/// Digits of the rewritten `startxref`, such that the length of the document does not depend on
+/// its placement.
+const STARTXREF_DIGITS: usize = 10;
+
+/// Rewrite the offsets in a PDF for its placement at `base` within a file.
+///
+/// The current placement is derived from the document itself, so a PDF that was relocated before
+/// can be relocated again. The result has the same length for every `base`.
+pub fn relocate(pdf: &[u8], base: usize) -> Result<Vec<u8>, InvalidPdfError> {
+    if !pdf.starts_with(b"%PDF-") {
+        return Err(invalid("the file does not start with a `%PDF-` header"));
+    }
+
+    let startxref = rfind(pdf, STARTXREF).ok_or_else(|| invalid("no `startxref` at the end"))?;
+    let value = number_after(pdf, startxref + STARTXREF.len())
+        .ok_or_else(|| invalid("`startxref` is not followed by an offset"))?;
+    let xref_offset = parse(&pdf[value.clone()]).ok_or_else(|| invalid("invalid `startxref`"))?;
+
+    // The table that `startxref` refers to is the last one in the file.
+    let xref = rfind(&pdf[..startxref], XREF)
+        .filter(|&at| !pdf[..at].ends_with(b"start") && pdf[at + XREF.len()].is_ascii_whitespace())
+        .ok_or_else(|| {
+            invalid("cross-reference streams are not supported, re-save the PDF with a table")
+        })?;
+
+    // Every offset in the document is shifted by the same amount from its position.
+    let shift = xref_offset as i64 - xref as i64;
+    let moved = |offset: usize, width: usize| -> Result<String, InvalidPdfError> {
+        let position = offset as i64 - shift;
+        if position < 0 || position as usize >= pdf.len() {
+            return Err(invalid("an object offset points outside the document"));
+        }
+
+        let moved = format!("{:0width$}", position as usize + base);
+        if moved.len() > width {
+            return Err(invalid(
+                "the packed file is too large for the cross-reference table",
+            ));
+        }
+
+        Ok(moved)
+    };
+
+    let mut out = pdf.to_vec();
+    let trailer = rewrite_table(&mut out, xref, &moved)?;
+    if trailer >= startxref {
+        return Err(invalid("the xref table runs past `startxref`"));
+    }
+
+    if find(&pdf[trailer..startxref], b"/Prev").is_some()
+        || find(&pdf[trailer..startxref], b"/XRefStm").is_some()
+    {
+        return Err(invalid(
+            "incremental updates are not supported, re-save the PDF without them",
+        ));
+    }
+
+    let startxref = moved(xref_offset, STARTXREF_DIGITS)?;
+    out.splice(value, startxref.into_bytes());
+
+    Ok(out)
+}
+
+/// Rewrite the offsets of all entries in use, returning the position of the trailer.
+fn rewrite_table(
+    pdf: &mut [u8],
+    xref: usize,
+    moved: &dyn Fn(usize, usize) -> Result<String, InvalidPdfError>,
+) -> Result<usize, InvalidPdfError> {
+    let mut at = skip_whitespace(pdf, xref + XREF.len());
+
+    // Subsections of `first count` followed by that many 20-byte entries.
+    while !pdf[at..].starts_with(TRAILER) {
+        let malformed = || invalid("malformed xref subsection");
+        let first = number_after(pdf, at).ok_or_else(malformed)?;
+        let digits = number_after(pdf, first.end).ok_or_else(malformed)?;
+        let count = parse(&pdf[digits.clone()]).ok_or_else(malformed)?;
+        at = skip_whitespace(pdf, digits.end);
+
+        for _ in 0..count {
+            let entry = pdf
+                .get(at..at + 18)
+                .ok_or_else(|| invalid("the xref table is truncated"))?;
+
+            if entry[17] == b'n' {
+                let offset = parse(&entry[..10]).ok_or_else(|| invalid("malformed xref entry"))?;
+                pdf[at..at + 10].copy_from_slice(moved(offset, 10)?.as_bytes());
+            }
+
+            // Entries should end with two bytes of whitespace, tolerate one.
+            at = skip_whitespace(pdf, at + 18);
+        }
+    }
+
+    Ok(at)
+}
+
+fn number_after(data: &[u8], at: usize) -> Option<Range<usize>> {
+    let start = skip_whitespace(data, at);
+    let len = data[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    (len > 0).then_some(start..start + len)
+}
+
+fn skip_whitespace(data: &[u8], at: usize) -> usize {
+    at + data[at..]
+        .iter()
+        .take_while(|b| b.is_ascii_whitespace())
+        .count()
+}
+
+fn parse(digits: &[u8]) -> Option<usize> {
+    core::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+fn invalid(reason: &'static str) -> InvalidPdfError {
+    InvalidPdfError {
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small document with a classic cross-reference table, and one free entry.
+    fn sample_pdf() -> Vec<u8> {
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 200 200] >>",
+        ];
+
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = vec![];
+        for (idx, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n{object}\nendobj\n", idx + 1).as_bytes());
+        }
+
+        let xref = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n", objects.len() + 1).as_bytes());
+        pdf.extend_from_slice(b"0000000000 65535 f \n");
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+        }
+
+        pdf.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+                objects.len() + 1
+            )
+            .as_bytes(),
+        );
+        pdf
+    }
+
+    /// Follow `startxref` to the table, and every entry in use to its object, as a reader does.
+    fn assert_offsets_point_at_objects(file: &[u8]) {
+        let startxref = rfind(file, STARTXREF).expect("a `startxref`");
+        let value = number_after(file, startxref + STARTXREF.len()).unwrap();
+        let xref = parse(&file[value]).unwrap();
+        assert!(file[xref..].starts_with(b"xref\n"), "`startxref` is {xref}");
+
+        let header = number_after(file, xref + XREF.len()).unwrap();
+        let first = parse(&file[header.clone()]).unwrap();
+        let count = number_after(file, header.end).unwrap();
+        let mut at = skip_whitespace(file, count.end);
+
+        let mut in_use = 0;
+        for number in first..first + parse(&file[count]).unwrap() {
+            let entry = &file[at..at + 20];
+            if entry[17] == b'n' {
+                let offset = parse(&entry[..10]).unwrap();
+                let object = format!("{number} 0 obj");
+                assert!(
+                    file[offset..].starts_with(object.as_bytes()),
+                    "entry {number} points at {offset}",
+                );
+                in_use += 1;
+            }
+            at += 20;
+        }
+
+        assert_eq!(in_use, 3);
+    }
+
+    #[test]
+    fn relocated_offsets_point_at_objects() {
+        let pdf = sample_pdf();
+        assert_offsets_point_at_objects(&pdf);
+
+        for base in [0, 1, 1000, 123_456] {
+            let relocated = relocate(&pdf, base).unwrap();
+            let mut file = vec![b' '; base];
+            file.extend_from_slice(&relocated);
+            assert_offsets_point_at_objects(&file);
+
+            // Relocating again derives the current placement from the document.
+            let again = relocate(&relocated, 7).unwrap();
+            assert_eq!(again.len(), relocated.len());
+            assert_offsets_point_at_objects(&[&b"1234567"[..], &again].concat());
+        }
+    }
+
+    #[test]
+    fn packed_document_is_the_pdf() {
+        let packer = crate::Packer::new("export default async function() {}")
+            .pdf(sample_pdf())
+            .trailing_zip(
+                b"PK\x05\x06"
+                    .iter()
+                    .chain(&[0; 18])
+                    .copied()
+                    .collect::<Vec<_>>(),
+            )
+            .target(crate::Target::PdfPlusWasm);
+
+        let document = packer.pack(b"\0asm\x01\0\0\0").unwrap();
+        // Readers look for the header in the first kilobyte.
+        assert!(find(&document[..1024], HEADER).is_some());
+        assert_offsets_point_at_objects(&document);
+
+        // Packed anew with a larger index HTML, the PDF moves back.
+        let repacked = crate::Packer::new("export default async function() {}")
+            .index_html("<p>Moves the document</p>".repeat(100))
+            .target(crate::Target::PdfPlusWasm)
+            .pack(&document)
+            .unwrap();
+        assert_offsets_point_at_objects(&repacked);
+    }
+
+    #[test]
+    fn rejects_incremental_updates() {
+        let mut pdf = sample_pdf();
+        let at = find(&pdf, b"/Root").unwrap();
+        pdf.splice(at..at, b"/Prev 9 ".iter().copied());
+        assert!(relocate(&pdf, 0).is_err());
+    }
+
+    #[test]
+    fn rejects_a_table_past_startxref() {
+        // The table has no trailer before `startxref`, its walk ends at the trailer after it.
+        let pdf = b"%PDF-1.0\nxref\n0 1\nstartxref\n9      x\ntrailer";
+        assert!(relocate(pdf, 0).is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Boots a packed document outside the browser, like the WASI stage 2 loader does within it.
+//!
+//! The `wah_wasi_config` section is converted to an instruction stream by the interpreter of
+//! `wasi-loader/interpret`, and the stream is then executed here just like `stage2-wasi.js`
+//! executes it: into arguments, environment variables and the file descriptors of a virtual file
+//! system. That file system, with `/boot/init`, `/proc/self/exe` and `/sbin/init`, is written to a
+//! directory on disk. With it as the root directory, an embedded WASI runtime runs stage 3 and
+//! then the application.
+//!
+//! Stage 3 gets the module on its standard input and unpacks the data archive. Documents without
+//! a `wah_polyglot_stage3` section have the archive unpacked by the runner instead.
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{archive, inspect::Document};
+
+/// The configuration when a document has no `wah_wasi_config` section, as in `stage2-wasi.js`.
+const DEFAULT_CONFIG: &[u8] = b"{}";
+
+/// The first stack index for results of instructions, below are the operations themselves.
+const OPS: usize = 256;
+
+/// Bytes of output of stage 3 kept for error messages.
+const CAPTURED_OUTPUT: usize = 1 << 20;
+
+/// The largest file that the configuration creates from a length, as it is held in memory.
+const MAX_FILE_LEN: u32 = 1 << 28;
+
+/// An entry of the virtual file system.
+#[derive(Clone, Debug)]
+pub enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+/// The environment that the instruction stream of a document configures.
+pub struct Boot {
+    pub args: Vec<String>,
+    /// Variables as `KEY=value`.
+    pub env: Vec<String>,
+    /// The standard input of stage 3.
+    pub stdin: Vec<u8>,
+    /// Where the root directory is mounted for the guest.
+    pub guest_root: String,
+    pub root: BTreeMap<String, Node>,
+}
+
+/// Options of a run, besides the document.
+#[derive(Clone, Debug, Default)]
+pub struct Runner {
+    root: Option<PathBuf>,
+    args: Vec<String>,
+}
+
+/// A value of the instruction stream, mirroring the objects of `stage2-wasi.js`.
+#[derive(Clone, Debug)]
+enum Value {
+    Undefined,
+    /// The configuration object, always at index 0.
+    Configuration,
+    /// A field of the configuration object, which instructions modify through it.
+    Field(&'static str),
+    Text(String),
+    Int(u32),
+    Bytes(Vec<u8>),
+    Object(BTreeMap<String, Value>),
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Value>),
+    Preopen(String, BTreeMap<String, Value>),
+    OpenFile(Vec<u8>),
+    Sections(Vec<Vec<u8>>),
+}
+
+#[derive(Default)]
+struct Configuration {
+    args: Vec<String>,
+    env: Vec<String>,
+    fds: Vec<Value>,
+}
+
+impl Runner {
+    /// Write the file system to this directory and keep it, instead of a temporary one.
+    pub fn root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.root = Some(dir.into());
+        self
+    }
+
+    /// Arguments to the application, after those of its configuration.
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.args.extend(args);
+        self
+    }
+
+    /// Run stage 3 and the application of a document, returning the exit code of the latter.
+    pub fn run(&self, document: &Document) -> Result<i32, Box<dyn Error>> {
+        // The module as a zip file, for the archive was placed with offsets into the whole file.
+        let mut module = document.module.to_vec();
+        let has_archive = document.archive().is_some();
+        if let Some(section) = document.archive() {
+            let range = section.range.clone();
+            archive::relocate(&mut module[range.clone()], range.start)?;
+        }
+
+        let boot = Boot::new(&module)?;
+
+        let (root, temporary) = match &self.root {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                (dir.clone(), false)
+            }
+            None => (temporary_root()?, true),
+        };
+
+        let archive = has_archive.then_some(module.as_slice());
+        let result = self.run_in(&boot, archive, &root);
+
+        if temporary {
+            std::fs::remove_dir_all(&root)?;
+        }
+
+        result
+    }
+
+    /// With the `archive`, the module, if stage 3 is missing and the archive needs unpacking.
+    fn run_in(
+        &self,
+        boot: &Boot,
+        archive: Option<&[u8]>,
+        root: &Path,
+    ) -> Result<i32, Box<dyn Error>> {
+        write_tree(root, &boot.root)?;
+
+        match boot.file("boot/init") {
+            Some(stage3) if !stage3.is_empty() => {
+                let process = Process {
+                    args: &boot.args,
+                    env: &boot.env,
+                    root,
+                    guest_root: &boot.guest_root,
+                };
+
+                let (code, stderr) = process.captured(stage3, &boot.stdin)?;
+                if code != 0 {
+                    return Err(format!(
+                        "stage 3 failed with exit code {code}: {}",
+                        String::from_utf8_lossy(&stderr),
+                    ))?;
+                }
+            }
+            _ => {
+                if let Some(module) = archive {
+                    let mut zip = zip::ZipArchive::new(Cursor::new(module))?;
+                    zip.extract(root)?;
+                }
+            }
+        }
+
+        let exe = boot
+            .file("sbin/init")
+            .ok_or("the configuration has no `/sbin/init` to run")?;
+
+        let args: Vec<String> = boot.args.iter().chain(&self.args).cloned().collect();
+        let process = Process {
+            args: &args,
+            env: &boot.env,
+            root,
+            guest_root: &boot.guest_root,
+        };
+
+        process.inherited(exe)
+    }
+}
+
+impl Boot {
+    /// Execute the instruction stream for the `wah_wasi_config` section of a module.
+    pub fn new(module: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let sections = custom_sections(module, "wah_wasi_config")?;
+        let config = match sections.as_slice() {
+            [] => DEFAULT_CONFIG,
+            [config] => config,
+            _ => return Err("multiple configuration sections `wah_wasi_config`")?,
+        };
+
+        let stream = wasi_loader::interpret(config);
+        let config = execute(&stream, module)?;
+
+        let bytes = |fd: usize| match config.fds.get(fd) {
+            Some(Value::OpenFile(data)) => Ok(data.clone()),
+            _ => Err(format!("file descriptor {fd} is not an open file")),
+        };
+
+        let stdin = bytes(0)?;
+        let Some(Value::Preopen(guest_root, root)) = config.fds.get(3) else {
+            return Err("file descriptor 3 is not a preopened directory")?;
+        };
+
+        Ok(Boot {
+            args: config.args,
+            env: config.env,
+            stdin,
+            guest_root: guest_root.clone(),
+            root: to_tree(root)?,
+        })
+    }
+
+    /// A file of the root directory, by a relative path.
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        let mut dir = &self.root;
+        let mut parts = path.split('/').peekable();
+
+        while let Some(part) = parts.next() {
+            match (dir.get(part)?, parts.peek()) {
+                (Node::File(data), None) => return Some(data),
+                (Node::Directory(entries), Some(_)) => dir = entries,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+}
+
+fn execute(stream: &[u8], module: &[u8]) -> Result<Configuration, Box<dyn Error>> {
+    let words: Vec<u32> = stream
+        .chunks_exact(4)
+        .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+        .collect();
+
+    let mut config = Configuration {
+        args: vec!["exe".into()],
+        ..Configuration::default()
+    };
+
+    let mut ops = vec![Value::Undefined; OPS];
+    ops[0] = Value::Configuration;
+
+    let mut iptr = 0;
+    while iptr < words.len() {
+        let op = words[iptr];
+        let count = words.get(iptr + 1).map_or(0, |&count| count as usize);
+        let args = words
+            .get(iptr + 2..iptr + 2 + count)
+            .ok_or("the instruction stream is truncated")?;
+
+        let data = |ptr: u32, len: u32| {
+            let start = ptr as usize;
+            stream
+                .get(start..start + len as usize)
+                .ok_or("an instruction refers to data outside the stream")
+        };
+
+        let at = |idx: u32| ops.get(idx as usize).unwrap_or(&Value::Undefined);
+
+        let value = match (op, args) {
+            (1, &[skip]) => {
+                iptr += skip as usize;
+                Value::Undefined
+            }
+            (2, &[ptr, len]) => Value::Text(String::from_utf8(data(ptr, len)?.to_vec())?),
+            (4, &[c, ..]) => Value::Int(c),
+            (5, &[ptr, len]) => Value::Bytes(data(ptr, len)?.to_vec()),
+            (6, &[from, idx]) => get(at(from), &key(at(idx))?, module),
+            (7, &[into, idx, what]) => {
+                let (key, what) = (key(at(idx))?, at(what).clone());
+                set(&mut ops, &mut config, into as usize, key, what.clone())?;
+                what
+            }
+            // A file of anything but bytes is created from a length, or empty.
+            (8, &[what]) => Value::File(match at(what) {
+                Value::Bytes(data) => data.clone(),
+                &Value::Int(len) if len <= MAX_FILE_LEN => vec![0; len as usize],
+                Value::Int(len) => {
+                    return Err(format!(
+                        "a file of {len} bytes exceeds {MAX_FILE_LEN} bytes"
+                    ))?
+                }
+                _ => vec![],
+            }),
+            (9, &[what]) => Value::Directory(object(at(what))?),
+            (10, &[path, what]) => Value::Preopen(key(at(path))?, object(at(what))?),
+            (11, &[dir, _, path, _]) => open(at(dir), &key(at(path))?)?,
+            (12, &[what]) => match at(what) {
+                Value::File(data) => Value::OpenFile(data.clone()),
+                _ => return Err("only files can be opened")?,
+            },
+            (13, &[name]) => Value::Sections(custom_sections(module, &key(at(name))?)?),
+            (14, _) => Value::Object(BTreeMap::new()),
+            (op, _) => {
+                return Err(format!(
+                    "instruction {op} with {count} arguments is not supported outside the browser"
+                ))?
+            }
+        };
+
+        ops.push(value);
+        iptr += 2 + count;
+    }
+
+    Ok(config)
+}
+
+fn get(from: &Value, key: &str, module: &[u8]) -> Value {
+    match (from, key) {
+        (Value::Configuration, "wasm") => Value::Bytes(module.to_vec()),
+        (Value::Configuration, "args") => Value::Field("args"),
+        (Value::Configuration, "env") => Value::Field("env"),
+        (Value::Configuration, "fds") => Value::Field("fds"),
+        (Value::Sections(sections), index) => index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| sections.get(index))
+            .map_or(Value::Undefined, |data| Value::Bytes(data.clone())),
+        (Value::Object(entries), key) => entries.get(key).cloned().unwrap_or(Value::Undefined),
+        _ => Value::Undefined,
+    }
+}
+
+fn set(
+    ops: &mut [Value],
+    config: &mut Configuration,
+    into: usize,
+    key: String,
+    what: Value,
+) -> Result<(), Box<dyn Error>> {
+    let index = || {
+        key.parse::<usize>()
+            .map_err(|_| format!("`{key}` is not an index"))
+    };
+
+    match ops.get_mut(into) {
+        Some(Value::Object(entries)) => {
+            entries.insert(key, what);
+        }
+        Some(Value::Field("fds")) => {
+            let index = index()?;
+            if config.fds.len() <= index {
+                config.fds.resize(index + 1, Value::Undefined);
+            }
+
+            config.fds[index] = what;
+        }
+        Some(Value::Field(field)) => {
+            let list = match *field {
+                "args" => &mut config.args,
+                _ => &mut config.env,
+            };
+
+            let Value::Text(text) = what else {
+                return Err(format!("only text can be assigned to `{field}`"))?;
+            };
+
+            let index = index()?;
+            if list.len() <= index {
+                list.resize(index + 1, String::new());
+            }
+
+            list[index] = text;
+        }
+        _ => return Err(format!("value {into} can not be assigned to"))?,
+    }
+
+    Ok(())
+}
+
+fn open(dir: &Value, path: &str) -> Result<Value, Box<dyn Error>> {
+    let (Value::Directory(mut entries) | Value::Preopen(_, mut entries)) = dir.clone() else {
+        return Err("only directories can open files")?;
+    };
+
+    let mut parts = path.split('/').filter(|part| !part.is_empty()).peekable();
+    while let Some(part) = parts.next() {
+        match (entries.remove(part), parts.peek()) {
+            (Some(Value::File(data)), None) => return Ok(Value::OpenFile(data)),
+            (Some(Value::Directory(inner)), Some(_)) => entries = inner,
+            _ => break,
+        }
+    }
+
+    Err(format!("`{path}` is not a file"))?
+}
+
+fn key(value: &Value) -> Result<String, Box<dyn Error>> {
+    match value {
+        Value::Text(text) => Ok(text.clone()),
+        Value::Int(int) => Ok(int.to_string()),
+        _ => Err("only text and integers can be keys")?,
+    }
+}
+
+fn object(value: &Value) -> Result<BTreeMap<String, Value>, Box<dyn Error>> {
+    match value {
+        Value::Object(entries) => Ok(entries.clone()),
+        _ => Err("directories are created from objects")?,
+    }
+}
+
+fn to_tree(entries: &BTreeMap<String, Value>) -> Result<BTreeMap<String, Node>, Box<dyn Error>> {
+    entries
+        .iter()
+        .map(|(name, value)| {
+            let node = match value {
+                Value::File(data) => Node::File(data.clone()),
+                Value::Directory(entries) => Node::Directory(to_tree(entries)?),
+                _ => return Err(format!("`{name}` is neither a file nor a directory"))?,
+            };
+
+            Ok((name.clone(), node))
+        })
+        .collect()
+}
+
+/// A directory of a random name that did not exist before, so nothing else shares it.
+fn temporary_root() -> Result<PathBuf, Box<dyn Error>> {
+    let mut random = [0; 16];
+    getrandom::getrandom(&mut random)
+        .map_err(|err| format!("no randomness for the root directory: {err}"))?;
+
+    let name: String = random.iter().map(|byte| format!("{byte:02x}")).collect();
+    let dir = std::env::temp_dir().join(format!("wasm-as-html-run-{name}"));
+    std::fs::create_dir(&dir)?;
+    Ok(dir)
+}
+
+fn write_tree(dir: &Path, entries: &BTreeMap<String, Node>) -> Result<(), Box<dyn Error>> {
+    for (name, node) in entries {
+        // Only a name within the directory, which `..` or an absolute path would leave.
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(format!("`{name}` is not usable as a file name").into());
+        }
+
+        let path = dir.join(name);
+        match node {
+            Node::File(data) => std::fs::write(&path, data)?,
+            Node::Directory(entries) => {
+                std::fs::create_dir_all(&path)?;
+                write_tree(&path, entries)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn custom_sections(module: &[u8], name: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut sections = vec![];
+
+    for payload in wasmparser::Parser::default().parse_all(module) {
+        let payload = payload.map_err(crate::error::InvalidModuleError::from)?;
+        if let wasmparser::Payload::CustomSection(custom) = payload {
+            if custom.name() == name {
+                sections.push(custom.data().to_vec());
+            }
+        }
+    }
+
+    Ok(sections)
+}
+
+/// A WASI process with the root directory mounted.
+struct Process<'run> {
+    args: &'run [String],
+    env: &'run [String],
+    root: &'run Path,
+    guest_root: &'run str,
+}
+
+impl Process<'_> {
+    /// Run with the given input, returning the exit code and the standard error output.
+    fn captured(&self, wasm: &[u8], stdin: &[u8]) -> Result<(i32, Vec<u8>), Box<dyn Error>> {
+        use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+
+        let stdout = MemoryOutputPipe::new(CAPTURED_OUTPUT);
+        let stderr = MemoryOutputPipe::new(CAPTURED_OUTPUT);
+
+        let code = self.execute(wasm, |builder| {
+            builder
+                .stdin(MemoryInputPipe::new(stdin.to_vec()))
+                .stdout(stdout.clone())
+                .stderr(stderr.clone());
+        })?;
+
+        Ok((code, stderr.contents().to_vec()))
+    }
+
+    fn inherited(&self, wasm: &[u8]) -> Result<i32, Box<dyn Error>> {
+        self.execute(wasm, |builder| {
+            builder.inherit_stdio();
+        })
+    }
+
+    fn execute(
+        &self,
+        wasm: &[u8],
+        stdio: impl FnOnce(&mut wasmtime_wasi::WasiCtxBuilder),
+    ) -> Result<i32, Box<dyn Error>> {
+        use wasmtime_wasi::{preview1, DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wasm)?;
+
+        let mut linker = wasmtime::Linker::new(&engine);
+        preview1::add_to_linker_sync(&mut linker, |ctx| ctx)?;
+
+        let mut builder = WasiCtxBuilder::new();
+        builder.args(self.args);
+        for var in self.env {
+            let (key, value) = var.split_once('=').unwrap_or((var, ""));
+            builder.env(key, value);
+        }
+
+        builder.preopened_dir(
+            self.root,
+            self.guest_root,
+            DirPerms::all(),
+            FilePerms::all(),
+        )?;
+        stdio(&mut builder);
+
+        let mut store = wasmtime::Store::new(&engine, builder.build_p1());
+        let instance = linker.instantiate(&mut store, &module)?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+
+        match start.call(&mut store, ()) {
+            Ok(()) => Ok(0),
+            Err(err) => match err.downcast_ref::<I32Exit>() {
+                Some(exit) => Ok(exit.0),
+                None => Err(err.into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_stays_within_the_root() {
+        let root = temporary_root().unwrap();
+        let file = |data: &[u8]| Node::File(data.to_vec());
+
+        let inner = BTreeMap::from([("init".to_string(), file(b"\0asm"))]);
+        let tree = BTreeMap::from([
+            ("boot".to_string(), Node::Directory(inner)),
+            ("readme".to_string(), file(b"text")),
+        ]);
+        write_tree(&root, &tree).unwrap();
+        assert_eq!(std::fs::read(root.join("boot/init")).unwrap(), b"\0asm");
+
+        for name in ["..", "../escaped", "/escaped", "a/b", "", "."] {
+            let tree = BTreeMap::from([(name.to_string(), file(b"text"))]);
+            assert!(write_tree(&root, &tree).is_err(), "{name:?}");
+        }
+
+        assert!(!root.parent().unwrap().join("escaped").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn temporary_roots_are_fresh() {
+        let (first, second) = (temporary_root().unwrap(), temporary_root().unwrap());
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_dir(&first).unwrap().count(), 0);
+
+        std::fs::remove_dir(first).unwrap();
+        std::fs::remove_dir(second).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! Encrypts a packed module with a password, for stage 0 to decrypt.
+//!
+//! Everything but the stage 0 and stage 1 loaders is packed into a module of its own, which is
+//! then sealed into the `wah_polyglot_encrypted` section of an outer module with just those
+//! loaders:
+//!
+//! ```text
+//! [1] [PBKDF2 iterations, u32 LE] [salt; 16] [nonce; 12] [AES-256-GCM ciphertext and tag ...]
+//! ```
+//!
+//! The key is derived with PBKDF2 and HMAC-SHA256, which WebCrypto provides in all browsers.
+//! Stage 0 asks for the password, opens the module and hands it to stage 1 as usual, [`unseal`]
+//! opens it natively.
+use core::error::Error;
+
+use aes_gcm::{
+    aead::{rand_core::RngCore as _, Aead as _, KeyInit as _, OsRng},
+    Aes256Gcm,
+};
+
+/// The name of the section with the sealed module.
+pub const SECTION: &str = "wah_polyglot_encrypted";
+
+const VERSION: u8 = 1;
+/// As recommended by OWASP for PBKDF2 with HMAC-SHA256.
+const ITERATIONS: u32 = 600_000;
+/// The version, iterations, salt and nonce before the ciphertext.
+const HEADER_LEN: usize = 1 + 4 + 16 + 12;
+
+/// Seal a module under a password, returning the contents of the section.
+pub fn seal(module: &[u8], password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    seal_with(module, password, ITERATIONS)
+}
+
+fn seal_with(module: &[u8], password: &str, iterations: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    if password.is_empty() {
+        Err("the password is empty")?;
+    }
+
+    let mut salt = [0; 16];
+    let mut nonce = [0; 12];
+    OsRng
+        .try_fill_bytes(&mut salt)
+        .and_then(|()| OsRng.try_fill_bytes(&mut nonce))
+        .map_err(|err| format!("no randomness for the encryption: {err}"))?;
+
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), &salt, iterations, &mut key);
+
+    let ciphertext = Aes256Gcm::new(&key.into())
+        .encrypt(&nonce.into(), module)
+        .map_err(|_| "the module is too large to encrypt")?;
+
+    let mut section = vec![VERSION];
+    section.extend_from_slice(&iterations.to_le_bytes());
+    section.extend_from_slice(&salt);
+    section.extend_from_slice(&nonce);
+    section.extend_from_slice(&ciphertext);
+    Ok(section)
+}
+
+/// Open the contents of a section with the password, as stage 0 does.
+pub fn unseal(section: &[u8], password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if section.len() < HEADER_LEN || section[0] != VERSION {
+        Err("the sealed module is not of a known version")?;
+    }
+
+    let iterations = u32::from_le_bytes(section[1..5].try_into().unwrap());
+    let (salt, nonce) = (&section[5..21], &section[21..HEADER_LEN]);
+
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), salt, iterations, &mut key);
+
+    Aes256Gcm::new(&key.into())
+        .decrypt(nonce.into(), &section[HEADER_LEN..])
+        .map_err(|_| "the password is wrong, or the sealed module was modified".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+    /// Few iterations, a debug build takes long for the real number.
+    const ITERATIONS: u32 = 1000;
+
+    fn seal(module: &[u8], password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        seal_with(module, password, ITERATIONS)
+    }
+
+    #[test]
+    fn unseals_with_the_password() {
+        let section = seal(MODULE, "correct horse").unwrap();
+        assert_eq!(section.len(), HEADER_LEN + MODULE.len() + 16);
+        assert_eq!(unseal(&section, "correct horse").unwrap(), MODULE);
+
+        // Fresh salt and nonce for every document.
+        assert_ne!(seal(MODULE, "correct horse").unwrap(), section);
+    }
+
+    #[test]
+    fn rejects_a_wrong_password() {
+        let section = seal(MODULE, "correct horse").unwrap();
+        assert!(unseal(&section, "battery staple").is_err());
+        assert!(unseal(&section, "").is_err());
+
+        let mut modified = section.clone();
+        *modified.last_mut().unwrap() ^= 1;
+        assert!(unseal(&modified, "correct horse").is_err());
+
+        assert!(seal(MODULE, "").is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! Signs a packed module with an ed25519 key, and verifies such signatures.
+//!
+//! The signature is a custom section of its own, `wah_polyglot_signature`, which holds a version,
+//! the public key, the signature and a signer identity:
+//!
+//! ```text
+//! [1] [public key; 32] [signature; 64] [signer, UTF-8 ...]
+//! ```
+//!
+//! It covers every section of the module except itself, the stage 0 and stage 1 loaders as well:
+//! they run first, so a document whose loaders were swapped must not pass as signed. Each covered
+//! section contributes its id, the length of its contents as four little endian bytes, and the
+//! contents. The message starts with [`DOMAIN`] and the signer identity, length prefixed in the
+//! same way. Both stage 1 loaders compute the same message from the module and verify it with
+//! WebCrypto, in `stage1-verify.js`.
+//!
+//! A valid signature only shows that the holder of the key in it signed the module. Who that is
+//! has to be checked against a key known by other means, the identity is a claim of the signer.
+//!
+//! The sections are signed as they are in the output, with the data archive relocated. Every
+//! target embeds the module in that form, so the signature holds no matter how it is stored.
+use core::error::Error;
+use std::ops::Range;
+
+use ed25519_dalek::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    Signature, Signer as _, SigningKey, VerifyingKey,
+};
+
+use crate::error::InvalidModuleError;
+
+/// The name of the signature section.
+pub const SECTION: &str = "wah_polyglot_signature";
+
+/// Separates these signatures from any others that the key makes.
+const DOMAIN: &[u8] = b"wasm-as-html signature\0";
+const VERSION: u8 = 2;
+/// How the public key is shown to users, before its hex digits.
+const FINGERPRINT_PREFIX: &str = "ed25519:";
+
+const KEY: Range<usize> = 1..33;
+const SIGNATURE: Range<usize> = 33..97;
+
+/// Who signed a module, as recorded in its signature.
+pub struct Signer {
+    /// The identity given when signing, which only the key vouches for.
+    pub identity: String,
+    pub public_key: [u8; 32],
+    /// Whether the key is the one expected by the verifier, otherwise the identity is unverified.
+    pub expected: bool,
+}
+
+/// Read an ed25519 private key from its PKCS#8 PEM form, as `openssl genpkey` writes it.
+pub fn signing_key(pem: &[u8]) -> Result<SigningKey, Box<dyn Error>> {
+    let pem = core::str::from_utf8(pem)?;
+    SigningKey::from_pkcs8_pem(pem).map_err(|err| {
+        format!("the signing key is not an ed25519 private key in PEM: {err}").into()
+    })
+}
+
+/// Read the public key that a signature is expected to have, from its fingerprint or a PEM file.
+///
+/// The fingerprint is the one that [`Signer::fingerprint`] shows. A file holds either the public
+/// key or the private key, in the PEM forms that `openssl pkey` writes.
+pub fn expected_key(key: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    if let Some(hex) = key.strip_prefix(FINGERPRINT_PREFIX) {
+        let digits = hex.as_bytes();
+        if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            Err(format!("the fingerprint `{key}` is not 64 hex digits"))?;
+        }
+
+        let mut public_key = [0; 32];
+        for (byte, pair) in public_key.iter_mut().zip(digits.chunks(2)) {
+            *byte = u8::from_str_radix(core::str::from_utf8(pair)?, 16)?;
+        }
+
+        return Ok(public_key);
+    }
+
+    let pem = std::fs::read_to_string(key)
+        .map_err(|err| format!("the expected key `{key}` is no fingerprint nor a file: {err}"))?;
+    let public_key = match VerifyingKey::from_public_key_pem(&pem) {
+        Ok(public_key) => public_key,
+        Err(_) => signing_key(pem.as_bytes())
+            .map_err(|_| format!("the expected key `{key}` is not an ed25519 key in PEM"))?
+            .verifying_key(),
+    };
+
+    Ok(public_key.to_bytes())
+}
+
+/// The contents of a signature section to reserve, before the module is complete.
+pub fn placeholder(identity: &str) -> Vec<u8> {
+    let mut section = vec![0; SIGNATURE.end];
+    section[0] = VERSION;
+    section.extend_from_slice(identity.as_bytes());
+    section
+}
+
+/// Sign a complete module in place, filling the reserved signature section.
+pub fn sign(module: &mut [u8], key: &SigningKey) -> Result<(), Box<dyn Error>> {
+    let message = message(module)?;
+    let section = message
+        .signature
+        .ok_or("the module has no signature section to fill")?;
+
+    let signature = key.sign(&message.bytes);
+    let contents = &mut module[section];
+    contents[KEY].copy_from_slice(key.verifying_key().as_bytes());
+    contents[SIGNATURE].copy_from_slice(&signature.to_bytes());
+    Ok(())
+}
+
+/// Verify the signature of a module, if it has one, and that it was made with the expected key.
+pub fn verify(
+    module: &[u8],
+    expected: Option<&[u8; 32]>,
+) -> Result<Option<Signer>, Box<dyn Error>> {
+    let message = message(module)?;
+    let Some(section) = message.signature else {
+        return Ok(None);
+    };
+
+    let contents = &module[section];
+    let public_key: [u8; 32] = contents[KEY].try_into().unwrap();
+    let signature = Signature::from_slice(&contents[SIGNATURE])?;
+
+    VerifyingKey::from_bytes(&public_key)?
+        .verify_strict(&message.bytes, &signature)
+        .map_err(|_| "the signature does not match the module, it was modified after signing")?;
+
+    let signer = Signer {
+        identity: String::from_utf8_lossy(&contents[SIGNATURE.end..]).into_owned(),
+        public_key,
+        expected: expected.is_some(),
+    };
+
+    if expected.is_some_and(|expected| *expected != public_key) {
+        Err(format!(
+            "the document is signed with {}, not the expected key",
+            signer.fingerprint()
+        ))?;
+    }
+
+    Ok(Some(signer))
+}
+
+/// What a signature section signs.
+struct Message {
+    /// The range of the signature section's contents, if there is one.
+    signature: Option<Range<usize>>,
+    bytes: Vec<u8>,
+}
+
+fn message(module: &[u8]) -> Result<Message, Box<dyn Error>> {
+    let mut signature = None;
+    let mut covered = vec![];
+
+    for payload in wasmparser::Parser::default().parse_all(module) {
+        let payload = payload.map_err(InvalidModuleError::from)?;
+
+        let Some((id, range)) = payload.as_section() else {
+            continue;
+        };
+
+        let wasmparser::Payload::CustomSection(custom) = &payload else {
+            covered.push((id, range));
+            continue;
+        };
+
+        if custom.name() == SECTION {
+            if signature.is_some() {
+                Err("the module has more than one signature section")?;
+            }
+
+            // The contents follow the name.
+            let contents = custom.data_offset()..range.end;
+            if contents.len() < SIGNATURE.end || module[contents.start] != VERSION {
+                Err("the signature section is not of a known version")?;
+            }
+
+            signature = Some(contents);
+        } else {
+            covered.push((id, range));
+        }
+    }
+
+    let identity = match &signature {
+        Some(contents) => &module[contents.start + SIGNATURE.end..contents.end],
+        None => &[],
+    };
+
+    let mut bytes = DOMAIN.to_vec();
+    bytes.extend_from_slice(&(identity.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(identity);
+
+    for (id, range) in covered {
+        bytes.push(id);
+        bytes.extend_from_slice(&(range.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&module[range]);
+    }
+
+    Ok(Message { signature, bytes })
+}
+
+impl Signer {
+    /// The public key as it is shown to users, in hex.
+    pub fn fingerprint(&self) -> String {
+        let hex: String = self.public_key.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{FINGERPRINT_PREFIX}{hex}")
+    }
+}
+
+impl core::fmt::Display for Signer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (self.identity.is_empty(), self.expected) {
+            (true, _) => write!(f, "{}", self.fingerprint()),
+            (false, true) => write!(f, "{} ({})", self.identity, self.fingerprint()),
+            (false, false) => write!(
+                f,
+                "{}, claiming to be {:?} (unverified)",
+                self.fingerprint(),
+                self.identity
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_module(key: &SigningKey) -> Vec<u8> {
+        let mut module = wasm_encoder::Module::new();
+        for (name, data) in [
+            ("wah_polyglot_stage0", &b"<script>stage 0</script>"[..]),
+            ("wah_polyglot_stage1", b"stage 1"),
+            ("wah_polyglot_stage2", b"stage 2"),
+            (SECTION, &placeholder("Me <me@example.org>")),
+        ] {
+            module.section(&wasm_encoder::CustomSection { name, data });
+        }
+
+        let mut module = module.finish();
+        sign(&mut module, key).unwrap();
+        module
+    }
+
+    #[test]
+    fn signature_covers_the_loaders() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let module = signed_module(&key);
+
+        let signer = verify(&module, None).unwrap().unwrap();
+        assert!(!signer.expected);
+        assert!(signer.to_string().ends_with("(unverified)"));
+
+        for loader in [&b"stage 0"[..], b"stage 1", b"stage 2"] {
+            let mut modified = module.clone();
+            let at = crate::inspect::find(&modified, loader).unwrap();
+            modified[at] ^= 0x20;
+            assert!(verify(&modified, None).is_err());
+        }
+    }
+
+    #[test]
+    fn signature_has_the_expected_key() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let module = signed_module(&key);
+
+        let fingerprint = verify(&module, None).unwrap().unwrap().fingerprint();
+        let expected = expected_key(&fingerprint).unwrap();
+        assert_eq!(expected, key.verifying_key().to_bytes());
+
+        let signer = verify(&module, Some(&expected)).unwrap().unwrap();
+        assert_eq!(
+            signer.to_string(),
+            format!("Me <me@example.org> ({fingerprint})")
+        );
+
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(verify(&module, Some(&other)).is_err());
+        assert!(expected_key("ed25519:00").is_err());
+    }
+}
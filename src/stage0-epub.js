@@ -0,0 +1,20 @@
+/** Loader, stage 0 for the `epub` target. The chapter around us is the index
+ * page converted to XHTML, which is what readers without scripting display.
+ * Where we do run, the module is a resource of the publication next to this
+ * chapter. Fetch it and hand off to stage 1 like the other entry points. On
+ * any failure the static chapter simply stays as it is.
+ */
+(async function() {
+  try {
+    let bytes = new Uint8Array(await (await fetch('wah-init.wasm')).arrayBuffer());
+    let wasm = await WebAssembly.compile(bytes);
+
+    let stage1 = WebAssembly.Module.customSections(wasm, 'wah_polyglot_stage1')[0];
+    let blob = new Blob([stage1], { type: 'application/javascript' });
+    let blobURL = URL.createObjectURL(blob);
+    let module = (await import(blobURL));
+    await module.default(bytes, wasm);
+  } catch (e) {
+    console.log('Wasm-As-HTML: keeping the static chapter', e);
+  }
+})();
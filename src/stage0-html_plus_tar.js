@@ -13,7 +13,8 @@
  * original module).
  *
  * The code here is quite self-contained with the main piece being an inlined
- * base64 decoder that is actually _correct_ for all inputs we throw at it.
+ * base64 decoder that is actually _correct_ for all inputs we throw at it, and
+ * a decoder for the denser base122 encoding.
  */
 
 // State object, introspectable for now.
@@ -41,6 +42,39 @@ function b64_decode(b64) {
   return view;
 }
 
+function b122_decode(text) {
+  /* See `html_and_tar::base122`, groups of 7 bits with the illegal ones in two-byte characters */
+  const ILLEGAL = [0x00, 0x0d, 0x26, 0x3c];
+  const view = new Uint8Array(Math.ceil(text.length * 14 / 8));
+
+  let i = 0;
+  let acc = 0;
+  let bits = 0;
+  function push(group) {
+    acc = (acc << 7) | group;
+    bits += 7;
+    if (bits >= 8) {
+      bits -= 8;
+      view[i++] = acc >> bits;
+      acc &= (1 << bits) - 1;
+    }
+  }
+
+  for (let j = 0; j < text.length; j++) {
+    const code = text.charCodeAt(j);
+    if (code < 0x80) {
+      push(code);
+    } else if (code >= 0x300) {
+      push(ILLEGAL[code - 0x300]);
+    } else {
+      push(ILLEGAL[(code >> 7) - 1]);
+      push(code & 0x7f);
+    }
+  }
+
+  return view.subarray(0, i);
+}
+
 window.addEventListener('load', async function() {
   console.debug('Wasm-As-HTML bootstrapping stage-0: started');
   const dataElements = document.getElementsByClassName('wah_polyglot_data');
@@ -67,29 +101,32 @@ window.addEventListener('load', async function() {
     // already is a pure text node. So its first child attribute is probably
     // synthetic and there's some encoding roundtrip which mangles it. Eh. This
     // is fine if it works and we do control the encoding side as well.
-    const b64 = el.content.textContent;
-    const raw_content = b64_decode(b64);
+    const text = el.content.textContent;
+    const raw_content = el.getAttribute('data-encoding') === 'base122'
+      ? b122_decode(text)
+      : b64_decode(text);
     global.file_data[givenName] = raw_content;
   }
 
-  const boot_wasm_bytes = global.file_data[BOOT];
+  let bytes = global.file_data[BOOT];
 
-  if (boot_wasm_bytes === undefined) {
+  if (bytes === undefined) {
     console.debug('Wasm-As-HTML bootstrapping stage-0: no handoff to boot, done');
     return;
   }
 
   let wasm = await WebAssembly.compileStreaming(
-    new Response(boot_wasm_bytes, { headers: { 'content-type': 'application/wasm' }})
+    new Response(bytes, { headers: { 'content-type': 'application/wasm' }})
   );
 
   try {
     let stage1 = WebAssembly.Module.customSections(wasm, 'wah_polyglot_stage1')[0];
+    /* __REPLACE_THIS_WITH_UNSEAL__ */
     let blob = new Blob([stage1], { type: 'application/javascript' });
     let blobURL = URL.createObjectURL(blob);
     let module = (await import(blobURL));
     console.debug('Wasm-As-HTML bootstrapping stage-0: handoff');
-    await module.default(boot_wasm_bytes, wasm);
+    await module.default(bytes, wasm);
   } catch (e) {
     console.error('Wasm-As-HTML failed to initialized', global, e);
   }
@@ -0,0 +1,32 @@
+#!/bin/sh
+# <!-- Executed, this file runs its WebAssembly module with a WASI runtime. In a
+# browser it is a web page instead, this comment hides the script until the
+# header of the module that follows, and the HTML of its first section, end it.
+# Hence the script itself must never contain the end of an HTML comment.
+module_offset=__REPLACE_THIS_WITH_MODULE_OFFSET__
+has_archive=__REPLACE_THIS_WITH_HAS_ARCHIVE__
+
+dir=$(mktemp -d) || exit 1
+trap 'rm -rf "$dir"' EXIT
+mkdir "$dir/data"
+tail -c +$((module_offset + 1)) "$0" > "$dir/module.wasm" || exit 1
+
+# The data archive is the last section of the module, its offsets count from the
+# start of this file.
+if [ "$has_archive" = 1 ]; then
+  if command -v unzip >/dev/null 2>&1; then
+    unzip -qo "$0" -d "$dir/data" || exit 1
+  else
+    echo "$0: unzip not found, running without the data archive" >&2
+  fi
+fi
+
+if command -v wasmtime >/dev/null 2>&1; then
+  wasmtime run --dir "$dir/data::/" "$dir/module.wasm" "$@"
+elif command -v wasmer >/dev/null 2>&1; then
+  wasmer run --mapdir "/:$dir/data" "$dir/module.wasm" -- "$@"
+else
+  echo "$0: running this file requires wasmtime or wasmer" >&2
+  exit 127
+fi
+exit $?
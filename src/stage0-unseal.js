@@ -0,0 +1,49 @@
+      /* Loader, stage 0 continued: the document is encrypted. Everything but
+       * stage 0 and 1 is one module, sealed with AES-GCM under a key derived
+       * from a password. Ask for the password, then continue with that module.
+       */
+      {
+        let sealed = new Uint8Array(WebAssembly.Module.customSections(wasm, 'wah_polyglot_encrypted')[0]);
+        if (sealed[0] !== 1) {
+          throw 'Found an encryption of an unknown version.';
+        }
+
+        let iterations = new DataView(sealed.buffer).getUint32(1, true);
+        let salt = sealed.slice(5, 21), iv = sealed.slice(21, 33), data = sealed.slice(33);
+
+        let form = document.createElement('form');
+        let input = form.appendChild(document.createElement('input'));
+        let button = form.appendChild(document.createElement('button'));
+        let status = form.appendChild(document.createElement('output'));
+        input.type = 'password';
+        input.placeholder = 'Password';
+        button.innerText = 'Open';
+
+        let notice = document.getElementById('stage0_error');
+        if (notice) {
+          notice.innerText = 'This document is encrypted.';
+          notice.after(form);
+        } else {
+          document.body.prepend(form);
+        }
+        input.focus();
+
+        for (bytes = null; !bytes;) {
+          let password = await new Promise(resolve => form.onsubmit = event => {
+            event.preventDefault();
+            resolve(input.value);
+          });
+
+          let material = await crypto.subtle.importKey(
+            'raw', new TextEncoder().encode(password), 'PBKDF2', false, ['deriveKey']);
+          let key = await crypto.subtle.deriveKey(
+            { name: 'PBKDF2', hash: 'SHA-256', salt, iterations },
+            material, { name: 'AES-GCM', length: 256 }, false, ['decrypt']);
+          bytes = await crypto.subtle.decrypt({ name: 'AES-GCM', iv }, key, data).then(
+            plain => new Uint8Array(plain),
+            () => { status.value = 'Wrong password'; return null; });
+        }
+
+        form.remove();
+        wasm = await WebAssembly.compile(bytes);
+      }
@@ -1,17 +1,25 @@
 async function init(bytes, wasm, initial) {
+  let signer = await verify(bytes, wasm);
+
   let index_html = WebAssembly.Module.customSections(wasm, 'wah_polyglot_stage1_html');
 
-  if (index_html.length) {
+  if (document.contentType === 'application/xhtml+xml') {
+    /* An EPUB chapter already is the index page, converted to XHTML by the packer */
+  } else if (index_html.length) {
     document.documentElement.innerHTML = (new TextDecoder().decode(index_html[0]));
   } else {
     document.getElementById('stage0_error').innerText = '';
   }
 
+  if (signer) {
+    show_signer(signer);
+  }
+
   let stage2 = WebAssembly.Module.customSections(wasm, 'wah_polyglot_stage2');
   if (!stage2.length) {
     throw 'Found no application data. Please check distribution.';
   }
-  if (!stage2.length > 1) {
+  if (stage2.length > 1) {
     throw 'Found duplicate application data. Please check distribution.';
   }
 
@@ -28,13 +36,40 @@ async function init(bytes, wasm, initial) {
   */
   function with_bytes(bytes) {
     let wasmblob = new Blob([bytes], { type: 'application/wasm' });
-    stage2_module.default(Promise.resolve(new Response(wasmblob)));
+    stage2_module.default(stage2_argument(Promise.resolve(new Response(wasmblob))));
+  }
+
+  /* A changed document runs only with a valid signature, of the same signer */
+  async function reload(bytes) {
+    try {
+      let now = await verify(bytes, await WebAssembly.compile(bytes));
+      if (signer && now !== signer) {
+        throw 'The changed document is no longer signed by ' + signer;
+      }
+    } catch (error) {
+      console.error('Wasm-As-HTML: not reloading,', error);
+      return;
+    }
+
+    with_bytes(bytes);
   }
 
   with_bytes(bytes);
-  refetch(bytes, with_bytes, 1000);
+  refetch(bytes, reload, 1000);
 }
 
+/** How the default export of stage 2 takes the module. The packer fills in
+ * the convention of the loader, see `src/bindgen.rs`: wasm-bindgen glue since
+ * 0.2.93 takes an object with a `module_or_path`, earlier glue and the WASI
+ * loader take the promise itself as `input`.
+ */
+function stage2_argument(response) {
+  let convention = '__REPLACE_THIS_WITH_STAGE2_CONVENTION__';
+  return convention === 'input' ? response : { module_or_path: response };
+}
+
+/* __REPLACE_THIS_WITH_VERIFY__ */
+
 async function refetch(bytes, onchange, interval) {
   async function identify(data) {
     let hashBuffer = await crypto.subtle.digest('SHA-256', data);
@@ -0,0 +1,83 @@
+/* Shared by `stage1.js` and `stage1-edit.js`, which the packer splices this into. */
+
+/** Verify the signature of a signed document, see `src/sign.rs` for its format.
+ * Returns who signed it, or null for a document without a signature. We do
+ * this before anything of the document runs or shows.
+ */
+async function verify(bytes, wasm) {
+  let signatures = WebAssembly.Module.customSections(wasm, 'wah_polyglot_signature');
+  if (!signatures.length) {
+    return null;
+  }
+  if (signatures.length > 1) {
+    throw 'Found duplicate signatures. Please check distribution.';
+  }
+
+  let signature = new Uint8Array(signatures[0]);
+  if (signature.length < 97 || signature[0] !== 2) {
+    throw 'Found a signature of an unknown version.';
+  }
+
+  let public_key = signature.slice(1, 33);
+  let identity = signature.slice(97);
+  let module = ArrayBuffer.isView(bytes)
+    ? new Uint8Array(bytes.buffer, bytes.byteOffset, bytes.byteLength)
+    : new Uint8Array(bytes);
+
+  function u32(value) {
+    let le = new Uint8Array(4);
+    new DataView(le.buffer).setUint32(0, value, true);
+    return le;
+  }
+
+  function leb128(data, offset) {
+    let value = 0, shift = 0, byte;
+    do {
+      byte = data[offset++];
+      value += (byte & 0x7f) * 2 ** shift;
+      shift += 7;
+    } while (byte & 0x80);
+    return [value, offset];
+  }
+
+  /* Every section but the signature, after the module header */
+  let parts = [new TextEncoder().encode('wasm-as-html signature\0'), u32(identity.length), identity];
+  let unsigned = ['wah_polyglot_signature'];
+
+  for (let offset = 8; offset < module.length;) {
+    let id = module[offset];
+    let [size, start] = leb128(module, offset + 1);
+    let contents = module.subarray(start, start + size);
+    offset = start + size;
+
+    if (id === 0) {
+      let [length, name_start] = leb128(contents, 0);
+      let name = new TextDecoder().decode(contents.subarray(name_start, name_start + length));
+      if (unsigned.includes(name)) {
+        continue;
+      }
+    }
+
+    parts.push(Uint8Array.of(id), u32(size), contents);
+  }
+
+  let message = await new Blob(parts).arrayBuffer();
+  let key = await crypto.subtle.importKey('raw', public_key, { name: 'Ed25519' }, false, ['verify']);
+  if (!await crypto.subtle.verify({ name: 'Ed25519' }, key, signature.slice(33, 97), message)) {
+    throw 'The signature does not match, the document was modified after signing.';
+  }
+
+  let hex = Array.from(public_key, b => b.toString(16).padStart(2, '0')).join('');
+  let name = new TextDecoder().decode(identity);
+  /* Only the key is vouched for, the page has no key to compare it with */
+  return name ? `ed25519:${hex}, claiming to be "${name}" (unverified)` : `ed25519:${hex}`;
+}
+
+/** Show the signer on the page, which the index HTML may style as `#wah_signature`. */
+function show_signer(signer) {
+  console.info('Wasm-As-HTML: signed by', signer);
+  let note = document.createElement('div');
+  note.id = 'wah_signature';
+  note.innerText = 'Signed by ' + signer;
+  document.body.append(note);
+}
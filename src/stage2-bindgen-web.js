@@ -0,0 +1,28 @@
+/* The builtin stage 2 loader for wasm-bindgen glue of the `web` target.
+ *
+ * The glue is specific to each application, so it is packed as the
+ * `wah_polyglot_wasm_bindgen` section of the module instead of as this stage.
+ * We import it like stage 1 imports us and hand over the module it was given.
+ */
+export default async function(input) {
+  let response = await (input?.module_or_path ?? input);
+  let bytes = await response.arrayBuffer();
+  let wasm = await WebAssembly.compile(bytes);
+
+  let bindgen = WebAssembly.Module.customSections(wasm, 'wah_polyglot_wasm_bindgen');
+  if (!bindgen.length) {
+    throw 'Found no wasm-bindgen glue. Pack it as the `wah_polyglot_wasm_bindgen` section.';
+  }
+  if (bindgen.length > 1) {
+    throw 'Found duplicate wasm-bindgen glue. Please check distribution.';
+  }
+
+  let blob = new Blob([bindgen[0]], { type: 'application/javascript' });
+  let blobURL = URL.createObjectURL(blob);
+  let glue = (await import(blobURL));
+
+  /* The module is compiled already, which the glue accepts in place of a
+   * path. The packer fills in the convention of the glue, see `src/bindgen.rs`. */
+  let convention = '__REPLACE_THIS_WITH_STAGE2_CONVENTION__';
+  return await glue.default(convention === 'input' ? wasm : { module_or_path: wasm });
+}
@@ -0,0 +1,124 @@
+/* The builtin stage 2 loader for Emscripten glue.
+ *
+ * The glue is packed as the `wah_polyglot_emscripten` section of the module.
+ * By itself it would fetch the module and preload its files from the server.
+ * Instead we instantiate the module of the document for it, and write the
+ * data archive into its file system before it runs.
+ */
+export default async function(input) {
+  let response = await (input?.module_or_path ?? input);
+  let bytes = await response.arrayBuffer();
+  let wasm = await WebAssembly.compile(bytes);
+
+  let glue = WebAssembly.Module.customSections(wasm, 'wah_polyglot_emscripten');
+  if (!glue.length) {
+    throw 'Found no Emscripten glue. Pack it as the `wah_polyglot_emscripten` section.';
+  }
+  if (glue.length > 1) {
+    throw 'Found duplicate Emscripten glue. Please check distribution.';
+  }
+
+  /* The packer fills in the section of the data archive, which may be renamed. */
+  let data_section = '__REPLACE_THIS_WITH_EMSCRIPTEN_DATA_SECTION__';
+  let files = await unzip(WebAssembly.Module.customSections(wasm, data_section));
+
+  let Module = {
+    /* The module is compiled already, the glue need not fetch it. */
+    instantiateWasm(imports, receive) {
+      WebAssembly.instantiate(wasm, imports).then(instance => receive(instance, wasm));
+      return {};
+    },
+    /* The data archive is the root of the file system, as for the `sh` target.
+     * The glue exports `FS` with `-sEXPORTED_RUNTIME_METHODS=FS`, which the
+     * packer requires of documents with an archive. */
+    preRun: [() => {
+      for (let [path, data] of files) {
+        let dir = data ? path.slice(0, Math.max(path.lastIndexOf('/'), 0)) : path;
+        if (dir) {
+          Module.FS.mkdirTree('/' + dir);
+        }
+        if (data) {
+          Module.FS.writeFile('/' + path, data);
+        }
+      }
+    }],
+  };
+
+  /* The packer tells how the glue was built, see `src/emscripten.rs`. */
+  let kind = '__REPLACE_THIS_WITH_EMSCRIPTEN_KIND__';
+  let blob = new Blob([glue[0]], { type: 'application/javascript' });
+  let blobURL = URL.createObjectURL(blob);
+
+  if (kind === 'module') {
+    /* With `-sMODULARIZE`, the glue exports a factory of the Module object. */
+    let factory = (await import(blobURL)).default;
+    return await factory(Module);
+  }
+
+  /* Otherwise it is a script that picks up a global Module object. */
+  globalThis.Module = Module;
+  await new Promise((resolve, reject) => {
+    let script = document.createElement('script');
+    script.src = blobURL;
+    script.onload = resolve;
+    script.onerror = reject;
+    (document.head ?? document.documentElement).appendChild(script);
+  });
+
+  return Module;
+}
+
+/** Read the entries of the data archive, as pairs of their path and contents.
+ * Directories have no contents. The packer relocated the offsets of the
+ * archive to where it is in the module, see `src/archive.rs`, so we count
+ * them from the central directory that ends the section.
+ */
+async function unzip(sections) {
+  let files = [];
+  if (!sections.length) {
+    return files;
+  }
+
+  let zip = new Uint8Array(sections[0]);
+  let view = new DataView(zip.buffer);
+
+  let end = zip.length - 22;
+  while (end >= 0 && view.getUint32(end, true) !== 0x06054b50) {
+    end--;
+  }
+  if (end < 0) {
+    throw 'The data archive is not a zip archive.';
+  }
+
+  let entries = view.getUint16(end + 10, true);
+  let at = end - view.getUint32(end + 12, true);
+  let shift = view.getUint32(end + 16, true) - at;
+
+  for (let i = 0; i < entries; i++) {
+    let method = view.getUint16(at + 10, true);
+    let size = view.getUint32(at + 20, true);
+    let name_len = view.getUint16(at + 28, true);
+    let local = view.getUint32(at + 42, true) - shift;
+    let path = new TextDecoder().decode(zip.subarray(at + 46, at + 46 + name_len));
+    at += 46 + name_len + view.getUint16(at + 30, true) + view.getUint16(at + 32, true);
+
+    if (path.endsWith('/')) {
+      files.push([path.slice(0, -1), null]);
+      continue;
+    }
+
+    let start = local + 30 + view.getUint16(local + 26, true) + view.getUint16(local + 28, true);
+    let data = zip.subarray(start, start + size);
+
+    if (method === 8) {
+      let inflated = new Blob([data]).stream().pipeThrough(new DecompressionStream('deflate-raw'));
+      data = new Uint8Array(await new Response(inflated).arrayBuffer());
+    } else if (method !== 0) {
+      throw `Found an unsupported compression for ${path} in the data archive.`;
+    }
+
+    files.push([path, data]);
+  }
+
+  return files;
+}
@@ -0,0 +1,42 @@
+/* The builtin stage 2 loader for Go and TinyGo modules.
+ *
+ * Their `wasm_exec.js` defines the `Go` class, which provides the imports of
+ * the module and runs it. It must match the toolchain of the module, so it is
+ * packed as the `wah_polyglot_go` section instead of shipping with us.
+ */
+export default async function(input) {
+  let response = await (input?.module_or_path ?? input);
+  let bytes = await response.arrayBuffer();
+  let wasm = await WebAssembly.compile(bytes);
+
+  let wasm_exec = WebAssembly.Module.customSections(wasm, 'wah_polyglot_go');
+  if (!wasm_exec.length) {
+    throw 'Found no wasm_exec.js. Pack it as the `wah_polyglot_go` section.';
+  }
+  if (wasm_exec.length > 1) {
+    throw 'Found duplicate wasm_exec.js. Please check distribution.';
+  }
+
+  /* It assigns `globalThis.Go` when evaluated, a module does that as well. */
+  let blob = new Blob([wasm_exec[0]], { type: 'application/javascript' });
+  await import(URL.createObjectURL(blob));
+
+  let go = new globalThis.Go();
+
+  /* The packer fills these in from the WASI configuration, see `src/go.rs`. */
+  let args = [/* __REPLACE_THIS_WITH_GO_ARGS__ */];
+  let env = [/* __REPLACE_THIS_WITH_GO_ENV__ */];
+
+  /* The program name comes first, as in `os.Args`, which `wasm_exec.js` calls `js`. */
+  go.argv = ['js', ...args];
+  go.env ??= {};
+  for (let variable of env) {
+    let [key, ...value] = variable.split('=');
+    go.env[key] = value.join('=');
+  }
+
+  let instance = await WebAssembly.instantiate(wasm, go.importObject);
+  /* Settles when the program exits, which a program serving callbacks never does. */
+  go.run(instance);
+  return go;
+}
@@ -0,0 +1,274 @@
+//! Stage 2 loaders that ship with the packer.
+//!
+//! Wherever a stage 2 loader is expected as a path, `builtin:<name>` selects one of these instead:
+//!
+//! * `builtin:wasi` is the WASI loader of `wasi-loader/`, which runs the module as a WASI command
+//!   in a virtual file system. Our `build.rs` bundles `stage2-wasi.js` with the WASI environment
+//!   of `wasi-shim.js`, so that no npm packages are needed.
+//! * `builtin:bindgen-web` runs wasm-bindgen glue of the `web` target, which it takes from the
+//!   `wah_polyglot_wasm_bindgen` section of the module. Add the glue as that section.
+//! * `builtin:emscripten` runs Emscripten glue from the `wah_polyglot_emscripten` section, see
+//!   [`crate::emscripten`].
+//! * `builtin:go` runs Go and TinyGo modules with the `wasm_exec.js` from the `wah_polyglot_go`
+//!   section, see [`crate::go`].
+//! * `builtin:auto` picks one of the above from the [`Flavor`] of the module.
+//!
+//! Which loader a module needs follows from the namespaces it imports. The packer refuses loaders
+//! that recognizably do not match, as the page would only fail once it is opened.
+use core::{error::Error, fmt, str::FromStr};
+use std::{collections::BTreeSet, path::Path};
+
+use crate::error::InvalidModuleError;
+
+/// Marks a stage 2 loader that is one of ours, rather than a path.
+pub const PREFIX: &str = "builtin:";
+/// The section that `builtin:bindgen-web` takes the wasm-bindgen glue from.
+pub const BINDGEN_SECTION: &str = "wah_polyglot_wasm_bindgen";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    Wasi,
+    BindgenWeb,
+    Emscripten,
+    Go,
+    /// Any of the others, depending on the module.
+    Auto,
+}
+
+/// The kind of host environment that a module expects, and the loader that provides it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    /// A WASI command, importing only `wasi_snapshot_preview1`.
+    Wasi,
+    /// A module built by wasm-bindgen, importing from `__wbindgen_placeholder__` or `wbg`.
+    Bindgen,
+    /// A module built by wasm-bindgen that imports WASI as well.
+    ///
+    /// The WASI loader runs it, with a stage 3 that provides its WASI imports to the glue, see
+    /// [`crate::bindgen`]. Glue itself can not.
+    BindgenWasi,
+    /// A module built by Emscripten, importing its `emscripten_` functions.
+    Emscripten,
+    /// A module of Go's `js/wasm` target, importing from `go` or `gojs`, also built by TinyGo.
+    Go,
+}
+
+/// Import namespaces of WASI modules.
+const WASI_NAMESPACES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+/// Import namespaces of wasm-bindgen modules, depending on its version.
+const BINDGEN_NAMESPACES: &[&str] = &["__wbindgen_placeholder__", "wbg"];
+/// Imports of Emscripten modules, which import WASI as well.
+const EMSCRIPTEN_PREFIXES: &[&str] = &["emscripten_", "_emscripten_"];
+/// Import namespaces of Go modules, depending on its version.
+const GO_NAMESPACES: &[&str] = &["go", "gojs"];
+
+impl Builtin {
+    pub const ALL: &[Builtin] = &[
+        Builtin::Wasi,
+        Builtin::BindgenWeb,
+        Builtin::Emscripten,
+        Builtin::Go,
+        Builtin::Auto,
+    ];
+
+    /// Whether a path names a builtin loader, and which one.
+    pub fn from_path(path: &Path) -> Option<Result<Self, String>> {
+        let name = path.to_str()?.strip_prefix(PREFIX)?;
+        Some(name.parse())
+    }
+
+    /// The source of the loader, a JS module.
+    ///
+    /// The `auto` loader has none by itself, resolve it with [`Flavor::builtin`] first.
+    pub fn source(self) -> Result<&'static [u8], Box<dyn Error>> {
+        match self {
+            // Bundled by our `build.rs`.
+            Builtin::Wasi => Ok(include_bytes!(concat!(env!("OUT_DIR"), "/builtin-wasi.js"))),
+            Builtin::BindgenWeb => Ok(include_bytes!("stage2-bindgen-web.js")),
+            Builtin::Emscripten => Ok(include_bytes!("stage2-emscripten.js")),
+            Builtin::Go => Ok(include_bytes!("stage2-go.js")),
+            Builtin::Auto => Err("`builtin:auto` selects a loader only once the module is known")?,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Builtin::Wasi => "wasi",
+            Builtin::BindgenWeb => "bindgen-web",
+            Builtin::Emscripten => "emscripten",
+            Builtin::Go => "go",
+            Builtin::Auto => "auto",
+        }
+    }
+}
+
+impl Flavor {
+    /// Tell the flavor of a module from the namespaces it imports.
+    ///
+    /// Modules that import nothing run with any loader, and others are not recognized, both have
+    /// no flavor.
+    pub fn of_module(wasm: &[u8]) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut namespaces = BTreeSet::new();
+        let mut emscripten = false;
+
+        for payload in wasmparser::Parser::default().parse_all(wasm) {
+            let payload = payload.map_err(InvalidModuleError::from)?;
+
+            if let wasmparser::Payload::ImportSection(imports) = payload {
+                for import in imports {
+                    let import = import.map_err(InvalidModuleError::from)?;
+                    namespaces.insert(import.module);
+                    emscripten |= EMSCRIPTEN_PREFIXES
+                        .iter()
+                        .any(|prefix| import.name.starts_with(prefix));
+                }
+            }
+        }
+
+        let wasi = namespaces.iter().any(|ns| WASI_NAMESPACES.contains(ns));
+
+        Ok(
+            if namespaces.iter().any(|ns| BINDGEN_NAMESPACES.contains(ns)) {
+                Some(if wasi {
+                    Flavor::BindgenWasi
+                } else {
+                    Flavor::Bindgen
+                })
+            } else if namespaces.iter().any(|ns| GO_NAMESPACES.contains(ns)) {
+                Some(Flavor::Go)
+            } else if emscripten {
+                Some(Flavor::Emscripten)
+            } else if !namespaces.is_empty()
+                && namespaces.iter().all(|ns| WASI_NAMESPACES.contains(ns))
+            {
+                Some(Flavor::Wasi)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Guess the flavor of a stage 2 loader from its source.
+    ///
+    /// The WASI loader reads the `wah_wasi_config` section, while wasm-bindgen glue defines its
+    /// `__wbindgen` intrinsics or is our loader for such glue. Our other loaders read their
+    /// sections. The Go loader reads no configuration, it is filled in when packing.
+    pub fn of_loader(source: &[u8]) -> Option<Self> {
+        let mentions = |what: &[u8]| crate::inspect::find(source, what).is_some();
+
+        let wasi = mentions(b"wah_wasi_config");
+        let bindgen = mentions(b"__wbindgen") || mentions(BINDGEN_SECTION.as_bytes());
+        let emscripten = mentions(crate::emscripten::SECTION.as_bytes());
+        let go = mentions(crate::go::SECTION.as_bytes());
+
+        match (wasi, bindgen, emscripten, go) {
+            (true, false, false, false) => Some(Flavor::Wasi),
+            (false, true, false, false) => Some(Flavor::Bindgen),
+            (false, false, true, false) => Some(Flavor::Emscripten),
+            (false, false, false, true) => Some(Flavor::Go),
+            _ => None,
+        }
+    }
+
+    /// The builtin loader for modules of this flavor.
+    pub fn builtin(self) -> Builtin {
+        match self {
+            Flavor::Wasi | Flavor::BindgenWasi => Builtin::Wasi,
+            Flavor::Bindgen => Builtin::BindgenWeb,
+            Flavor::Emscripten => Builtin::Emscripten,
+            Flavor::Go => Builtin::Go,
+        }
+    }
+
+    /// Refuse a loader of a different flavor than the module.
+    ///
+    /// Loaders we can not tell the flavor of are fine, custom ones may well handle the module.
+    pub fn check_loader(module: Option<Self>, source: &[u8]) -> Result<(), Box<dyn Error>> {
+        let (Some(module), Some(loader)) = (module, Flavor::of_loader(source)) else {
+            return Ok(());
+        };
+
+        let fits = match module {
+            Flavor::BindgenWasi => matches!(loader, Flavor::Wasi | Flavor::Bindgen),
+            module => module == loader,
+        };
+
+        if !fits {
+            return Err(format!(
+                "{}, but the stage 2 loader is {}. Pack it with `{}` or `{}` instead",
+                module.module_description(),
+                loader.loader_description(),
+                module.builtin(),
+                Builtin::Auto,
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    fn module_description(self) -> &'static str {
+        match self {
+            Flavor::Wasi => "the module is a WASI command",
+            Flavor::Bindgen => "the module was built with wasm-bindgen",
+            Flavor::BindgenWasi => "the module was built with wasm-bindgen and imports WASI",
+            Flavor::Emscripten => "the module was built with Emscripten",
+            Flavor::Go => "the module was built by Go or TinyGo",
+        }
+    }
+
+    fn loader_description(self) -> &'static str {
+        match self {
+            Flavor::Wasi => "for WASI commands",
+            Flavor::Bindgen => "for wasm-bindgen modules",
+            Flavor::BindgenWasi => "for wasm-bindgen modules that import WASI",
+            Flavor::Emscripten => "for Emscripten modules",
+            Flavor::Go => "for Go modules",
+        }
+    }
+}
+
+impl FromStr for Builtin {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        Builtin::ALL
+            .iter()
+            .copied()
+            .find(|builtin| builtin.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Builtin::ALL
+                    .iter()
+                    .map(|builtin| format!("`{builtin}`"))
+                    .collect();
+
+                format!(
+                    "no builtin stage 2 loader `{PREFIX}{name}`, expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX}{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wasi_loader_is_bundled() {
+        let source = Builtin::Wasi.source().unwrap();
+        let text = core::str::from_utf8(source).unwrap();
+
+        // Nothing is left for a bundler to resolve.
+        assert!(!text.lines().any(|line| line.starts_with("import ")));
+        assert!(text.contains("class PreopenDirectory"));
+        assert!(text.contains("export default mount;"));
+        assert_eq!(Flavor::of_loader(source), Some(Flavor::Wasi));
+        assert!(Flavor::check_loader(Some(Flavor::Wasi), source).is_ok());
+    }
+}
@@ -0,0 +1,132 @@
+//! Strips the polyglot structure from a packed file to recover the original module.
+//!
+//! The packer copies every section of the original module verbatim, in order, between the stage2
+//! section and any added sections. The sections it adds itself are all named with a reserved
+//! prefix, except for a data archive that was renamed with `--trailing-zip-section` which is
+//! recognized as the final zip section instead, see [`Document::archive`]. Sections added with
+//! `--add-section` under any other name are indistinguishable from the module's own custom
+//! sections and are kept.
+//!
+//! The module is re-encoded from its sections just like the packer encoded them, which restores
+//! the original bytes for any module using the minimal encoding of section sizes.
+use core::error::Error;
+use std::path::Path;
+
+use crate::error::UnrecognizedDocumentError;
+use crate::inspect::Document;
+
+/// Files to write into the unpack directory, for sections that have a conventional extension.
+const SECTION_FILES: &[(&str, &str)] = &[
+    ("wah_polyglot_stage2", "stage2.js"),
+    ("wah_polyglot_stage1_html", "index.html"),
+    ("wah_polyglot_pdf", "document.pdf"),
+];
+
+const ARCHIVE_FILE: &str = "data.zip";
+
+/// Sections that the packer makes itself, there is nothing to recover from them.
+const BUILTIN: &[&str] = &[
+    "wah_polyglot_stage0",
+    "wah_polyglot_stage1",
+    crate::sign::SECTION,
+];
+
+pub struct Unpacked<'doc> {
+    /// The original module, as it was passed to the packer.
+    pub module: Vec<u8>,
+    /// The trailing data archive, with its section name.
+    pub archive: Option<(&'doc str, &'doc [u8])>,
+    /// All other sections that the packer added, except its builtin loaders and the signature.
+    pub added: Vec<(&'doc str, &'doc [u8])>,
+}
+
+pub fn unpack<'doc>(document: &'doc Document) -> Unpacked<'doc> {
+    let mut encoder = wasm_encoder::Module::new();
+    let mut archive = None;
+    let mut added = vec![];
+
+    let archive_range = document.archive().map(|section| section.range.clone());
+
+    for section in &document.sections {
+        let data = &document.module[section.range.clone()];
+
+        let Some(name) = &section.name else {
+            encoder.section(&wasm_encoder::RawSection {
+                id: section.id,
+                data,
+            });
+
+            continue;
+        };
+
+        if archive_range.as_ref() == Some(&section.range) {
+            archive = Some((name.as_str(), data));
+        } else if !is_packer_section(name) {
+            encoder.section(&wasm_encoder::CustomSection { name, data });
+        } else if !BUILTIN.contains(&name.as_str()) {
+            added.push((name.as_str(), data));
+        }
+    }
+
+    Unpacked {
+        module: encoder.finish(),
+        archive,
+        added,
+    }
+}
+
+fn is_packer_section(name: &str) -> bool {
+    name.starts_with("wah_polyglot_") || name == "wah_wasi_config"
+}
+
+impl<'doc> Unpacked<'doc> {
+    /// The contents of the first added section with that name.
+    pub fn section(&self, name: &str) -> Option<&'doc [u8]> {
+        self.added
+            .iter()
+            .find(|&&(section, _)| section == name)
+            .map(|&(_, data)| data)
+    }
+
+    /// Write all recovered sections as files into a directory.
+    ///
+    /// Known sections are named by their role, such as `stage2.js`. Others are written with the
+    /// name of their section so they can be passed back to the packer with `--add-section`.
+    pub fn write_to_dir(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+
+        if let Some((_, archive)) = self.archive {
+            // In the document its offsets count from the start of the module.
+            let mut archive = archive.to_vec();
+            crate::archive::relocate(&mut archive, 0)?;
+            std::fs::write(dir.join(ARCHIVE_FILE), archive)?;
+        }
+
+        for &(name, data) in &self.added {
+            let file = SECTION_FILES
+                .iter()
+                .find(|&&(section, _)| section == name)
+                .map_or(name, |&(_, file)| file);
+
+            // The names come from the document, do not let them escape the directory.
+            let mut components = Path::new(file).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(std::path::Component::Normal(_)), None)
+            ) {
+                return Err(Box::new(UnrecognizedDocumentError {
+                    reason: format!("section name `{name}` is not usable as a file name").into(),
+                }));
+            }
+
+            // Like the archive, the PDF is only readable at the start of a file again.
+            if name == "wah_polyglot_pdf" {
+                std::fs::write(dir.join(file), crate::pdf::relocate(data, 0)?)?;
+            } else {
+                std::fs::write(dir.join(file), data)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -9,42 +9,25 @@ export default async function(configuration) {
    *     // etc.
    *     imports['wasi_snapshot_preview1'] = __wbg_star0;
    *
-   * Okay. So can we setup such that the above `wasi_snapshot_preview1` module
-   * refers to some shim that we control? Not so easy. We can not simply create
-   * an importmap; we're already running in Js context and it's forbidden to
-   * modify after that (with some funny interaction when rewriting the whole
-   * document where warnings are swallowed?). See `__not_working_via_importmap`.
-   *
-   * Instead, we will hackishly rewrite the bindgen import if we're asked to.
-   * Create a shim module that exports the wasi objects' import map, and
-   * communicate with the shim module via a global for lack of better ideas. I
-   * don't like that we can not reverse this, the module is cached, but oh
-   * well. Let's hope for wasm-bindgen to cave at some point. Or the browser
-   * but 'Chromium does not have the bandwidth' to implement the dynamic remap
-   * feature already in much smaller products. And apparently that is the
-   * motivation not to move forward in WICG. Just ____ off. When talking about
-   * Chrome monopoly leading to bad outcomes, this is one. And no one in
-   * particular is at fault of course.
+   * A page can not resolve such a bare specifier, and an importmap can not be
+   * added once scripts run. See `__not_working_via_importmap`. The packer
+   * instead rewrites these imports in the `wah_polyglot_wasm_bindgen` section
+   * and has the glue export a hook, `wah_provide_imports`, with which we hand
+   * it the namespaces before initializing. See `src/bindgen.rs`.
    */
   console.log('Reached stage3 successfully', configuration);
   const wasm = configuration.wasm_module;
 
   let newWasi = new configuration.WASI(configuration.args, configuration.env, configuration.fds);
-  document.__wah_wasi_imports = newWasi.wasiImport;
-
-  let testmodule = Object.keys(document.__wah_wasi_imports)
-    .map((name, _) => `export const ${name} = document.__wah_wasi_imports.${name};`)
-    .join('\n');
-  let wasi_blob = new Blob([testmodule], { type: 'application/javascript' });
-  let objecturl = URL.createObjectURL(wasi_blob);
 
   const bindgens = WebAssembly.Module.customSections(wasm, 'wah_polyglot_wasm_bindgen');
-  const wbg_source = new TextDecoder().decode(bindgens[0]).replace('wasi_snapshot_preview1', objecturl);
-
-  let wbg_blob = new Blob([wbg_source], { type: 'application/javascript' });
+  let wbg_blob = new Blob([bindgens[0]], { type: 'application/javascript' });
   let wbg_url = URL.createObjectURL(wbg_blob);
   const m = await import(wbg_url);
 
+  /* Glue of modules without WASI imports has no hook. */
+  m.wah_provide_imports?.({ wasi_snapshot_preview1: newWasi.wasiImport });
+
   const index_html = WebAssembly.Module.customSections(wasm, 'wah_polyglot_stage1_html');
   document.documentElement.innerHTML = (new TextDecoder().decode(index_html[0]));
 
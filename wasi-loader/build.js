@@ -124,6 +124,7 @@ let wasiInterpreterPlugin = {
 
 await esbuild.build({
   entryPoints: ['stage2-wasi.js'],
+  inject: ['imports.js'],
   bundle: true,
   outfile: 'out.js',
   format: 'esm',
@@ -0,0 +1,5 @@
+// The names that `stage2-wasi.js` uses without importing them, which esbuild
+// injects wherever they are used.
+export { WASI, File, OpenFile, Directory, PreopenDirectory } from "@bjorn3/browser_wasi_shim";
+// This include is synthesized by `build.js:wasiInterpreterPlugin`.
+export { load_config } from 'wasi-config:config.toml';
@@ -1,6 +1,6 @@
-import { WASI, File, OpenFile, Directory, PreopenDirectory } from "@bjorn3/browser_wasi_shim";
-// This include is synthesized by `build.js:wasiInterpreterPlugin`.
-import { load_config } from 'wasi-config:config.toml'
+// `WASI`, `File`, `OpenFile`, `Directory` and `PreopenDirectory` of the WASI
+// environment, and `load_config`, are defined ahead of this file: `build.js`
+// injects them from `imports.js`, the packer's `build.rs` concatenates them.
 
 async function fallback_shell(configuration, error) {
   document.documentElement.innerHTML = `<p>Missing boot exec</p>`;
@@ -72,7 +72,9 @@ async function mount(promise) {
   };
 
   let wah_wasi_config_data = WebAssembly.Module.customSections(wasm, 'wah_wasi_config');
-  wah_wasi_config_data.unshift(new TextEncoder('utf-8').encode('{}'));
+  if (!wah_wasi_config_data.length) {
+    wah_wasi_config_data.push(new TextEncoder('utf-8').encode('{}'));
+  }
 
   if (wah_wasi_config_data.length > 1) {
     // We can not handle this. Okay, granted, we could somehow put it into the
@@ -0,0 +1,626 @@
+/* A WASI preview 1 environment over an in-memory file system.
+ *
+ * It provides the part of the `@bjorn3/browser_wasi_shim` interface that the
+ * loader, the configuration interpreter and stage 3 use: `WASI` with its
+ * `wasiImport` and `start`, `File`, `Directory`, `OpenFile`, `OpenDirectory`
+ * and `PreopenDirectory` with `path_open`. The packer's `build.rs` bundles it
+ * as `builtin:wasi`, so that no npm package is needed to build the packer.
+ *
+ * It is a script rather than a module: the bundle concatenates it ahead of
+ * `stage2-wasi.js`, which uses its classes as `imports.js` would provide them.
+ * See `wasi-shim.test.mjs` for its tests.
+ */
+
+const ERRNO_SUCCESS = 0;
+const ERRNO_BADF = 8;
+const ERRNO_EXIST = 20;
+const ERRNO_INVAL = 28;
+const ERRNO_ISDIR = 31;
+const ERRNO_NOENT = 44;
+const ERRNO_NOSYS = 52;
+const ERRNO_NOTDIR = 54;
+const ERRNO_NOTEMPTY = 55;
+const ERRNO_NOTSUP = 58;
+const ERRNO_NOTCAPABLE = 76;
+
+const FILETYPE_DIRECTORY = 3;
+const FILETYPE_REGULAR_FILE = 4;
+
+const OFLAGS_CREAT = 1;
+const OFLAGS_DIRECTORY = 2;
+const OFLAGS_EXCL = 4;
+const OFLAGS_TRUNC = 8;
+
+const FDFLAGS_APPEND = 1;
+
+const WHENCE_SET = 0;
+const WHENCE_CUR = 1;
+const WHENCE_END = 2;
+
+const CLOCKID_REALTIME = 0;
+
+/* Every right, this file system does not restrict any operation. */
+const RIGHTS_ALL = 0x1fffffffn;
+
+class WASIProcExit extends Error {
+  constructor(code) {
+    super(`exit with exit code ${code}`);
+    this.code = code;
+  }
+}
+
+class File {
+  constructor(data) {
+    this.data = new Uint8Array(data);
+  }
+
+  get size() {
+    return this.data.byteLength;
+  }
+
+  stat() {
+    return { filetype: FILETYPE_REGULAR_FILE, size: BigInt(this.size) };
+  }
+
+  /* Grow or shrink to a length, filling with zeros. */
+  resize(length) {
+    let data = new Uint8Array(length);
+    data.set(this.data.subarray(0, length));
+    this.data = data;
+  }
+}
+
+class Directory {
+  /* The contents map names to files and directories, as a plain object. */
+  constructor(contents) {
+    this.contents = contents;
+  }
+
+  stat() {
+    return { filetype: FILETYPE_DIRECTORY, size: 0n };
+  }
+
+  entry(name) {
+    return Object.hasOwn(this.contents, name) ? this.contents[name] : null;
+  }
+}
+
+/* The components of a relative path, or null if it leaves the directory. */
+function components(path) {
+  let parts = [];
+  for (let part of path.split('/')) {
+    if (part === '' || part === '.') {
+      continue;
+    } else if (part === '..') {
+      if (!parts.length) {
+        return null;
+      }
+      parts.pop();
+    } else {
+      parts.push(part);
+    }
+  }
+  return parts;
+}
+
+class Fd {
+  fd_close() { return ERRNO_SUCCESS; }
+  fd_fdstat_get() { return { ret: ERRNO_BADF, fdstat: null }; }
+  fd_filestat_get() { return { ret: ERRNO_BADF, filestat: null }; }
+  fd_filestat_set_size() { return ERRNO_BADF; }
+  fd_prestat_get() { return { ret: ERRNO_BADF, prestat: null }; }
+  fd_read() { return { ret: ERRNO_BADF, nread: 0 }; }
+  fd_pread() { return { ret: ERRNO_BADF, nread: 0 }; }
+  fd_write() { return { ret: ERRNO_BADF, nwritten: 0 }; }
+  fd_pwrite() { return { ret: ERRNO_BADF, nwritten: 0 }; }
+  fd_seek() { return { ret: ERRNO_BADF, offset: 0n }; }
+  fd_readdir_entries() { return { ret: ERRNO_BADF, entries: null }; }
+  path_open() { return { ret: ERRNO_NOTDIR, fd_obj: null }; }
+  path_create_directory() { return ERRNO_NOTDIR; }
+  path_filestat_get() { return { ret: ERRNO_NOTDIR, filestat: null }; }
+  path_remove(path, directory) { return ERRNO_NOTDIR; }
+  path_lookup() { return { ret: ERRNO_NOTDIR, parent: null, name: null }; }
+}
+
+class OpenFile extends Fd {
+  constructor(file, fdflags = 0) {
+    super();
+    this.file = file;
+    this.file_pos = 0n;
+    this.fdflags = fdflags;
+  }
+
+  fd_fdstat_get() {
+    return { ret: ERRNO_SUCCESS, fdstat: { filetype: FILETYPE_REGULAR_FILE, flags: this.fdflags } };
+  }
+
+  fd_filestat_get() {
+    return { ret: ERRNO_SUCCESS, filestat: this.file.stat() };
+  }
+
+  fd_filestat_set_size(size) {
+    this.file.resize(Number(size));
+    return ERRNO_SUCCESS;
+  }
+
+  fd_read(view8, iovs) {
+    let { ret, nread } = this.fd_pread(view8, iovs, this.file_pos);
+    this.file_pos += BigInt(nread);
+    return { ret, nread };
+  }
+
+  fd_pread(view8, iovs, offset) {
+    let position = Number(offset);
+    let nread = 0;
+    for (let iov of iovs) {
+      let chunk = this.file.data.subarray(position, position + iov.buf_len);
+      view8.set(chunk, iov.buf);
+      position += chunk.length;
+      nread += chunk.length;
+      if (chunk.length < iov.buf_len) {
+        break;
+      }
+    }
+    return { ret: ERRNO_SUCCESS, nread };
+  }
+
+  fd_write(view8, iovs) {
+    if (this.fdflags & FDFLAGS_APPEND) {
+      this.file_pos = BigInt(this.file.size);
+    }
+    let { ret, nwritten } = this.fd_pwrite(view8, iovs, this.file_pos);
+    this.file_pos += BigInt(nwritten);
+    return { ret, nwritten };
+  }
+
+  fd_pwrite(view8, iovs, offset) {
+    let position = Number(offset);
+    let length = iovs.reduce((sum, iov) => sum + iov.buf_len, 0);
+    if (position + length > this.file.size) {
+      this.file.resize(position + length);
+    }
+    for (let iov of iovs) {
+      this.file.data.set(view8.subarray(iov.buf, iov.buf + iov.buf_len), position);
+      position += iov.buf_len;
+    }
+    return { ret: ERRNO_SUCCESS, nwritten: length };
+  }
+
+  fd_seek(offset, whence) {
+    let base;
+    switch (whence) {
+      case WHENCE_SET: base = 0n; break;
+      case WHENCE_CUR: base = this.file_pos; break;
+      case WHENCE_END: base = BigInt(this.file.size); break;
+      default: return { ret: ERRNO_INVAL, offset: 0n };
+    }
+    if (base + offset < 0n) {
+      return { ret: ERRNO_INVAL, offset: 0n };
+    }
+    this.file_pos = base + offset;
+    return { ret: ERRNO_SUCCESS, offset: this.file_pos };
+  }
+}
+
+class OpenDirectory extends Fd {
+  constructor(dir) {
+    super();
+    this.dir = dir;
+  }
+
+  fd_fdstat_get() {
+    return { ret: ERRNO_SUCCESS, fdstat: { filetype: FILETYPE_DIRECTORY, flags: 0 } };
+  }
+
+  fd_filestat_get() {
+    return { ret: ERRNO_SUCCESS, filestat: this.dir.stat() };
+  }
+
+  fd_readdir_entries() {
+    let entries = [['.', this.dir], ['..', this.dir], ...Object.entries(this.dir.contents)];
+    return { ret: ERRNO_SUCCESS, entries };
+  }
+
+  /* The directory that holds the last component of a path, and that name. */
+  path_lookup(path) {
+    let parts = components(path);
+    if (parts === null) {
+      return { ret: ERRNO_NOTCAPABLE, parent: null, name: null };
+    }
+
+    let parent = this.dir;
+    let name = parts.pop() ?? '.';
+    for (let part of parts) {
+      let entry = parent.entry(part);
+      if (entry === null) {
+        return { ret: ERRNO_NOENT, parent: null, name: null };
+      } else if (!(entry instanceof Directory)) {
+        return { ret: ERRNO_NOTDIR, parent: null, name: null };
+      }
+      parent = entry;
+    }
+    return { ret: ERRNO_SUCCESS, parent, name };
+  }
+
+  /* The entry at a path, which is this directory for an empty path. */
+  path_entry(path) {
+    let { ret, parent, name } = this.path_lookup(path);
+    if (ret !== ERRNO_SUCCESS) {
+      return { ret, entry: null };
+    }
+    let entry = name === '.' ? parent : parent.entry(name);
+    return entry === null ? { ret: ERRNO_NOENT, entry } : { ret: ERRNO_SUCCESS, entry };
+  }
+
+  path_open(dirflags, path, oflags, fs_rights_base, fs_rights_inheriting, fdflags = 0) {
+    let { ret, parent, name } = this.path_lookup(path);
+    if (ret !== ERRNO_SUCCESS) {
+      return { ret, fd_obj: null };
+    }
+
+    let entry = name === '.' ? parent : parent.entry(name);
+    if (entry === null) {
+      if (!(oflags & OFLAGS_CREAT)) {
+        return { ret: ERRNO_NOENT, fd_obj: null };
+      } else if (name === '__proto__') {
+        return { ret: ERRNO_INVAL, fd_obj: null };
+      }
+      entry = parent.contents[name] = new File([]);
+    } else if (oflags & OFLAGS_EXCL) {
+      return { ret: ERRNO_EXIST, fd_obj: null };
+    }
+
+    if (entry instanceof Directory) {
+      return { ret: ERRNO_SUCCESS, fd_obj: new OpenDirectory(entry) };
+    } else if (oflags & OFLAGS_DIRECTORY) {
+      return { ret: ERRNO_NOTDIR, fd_obj: null };
+    }
+
+    if (oflags & OFLAGS_TRUNC) {
+      entry.resize(0);
+    }
+    return { ret: ERRNO_SUCCESS, fd_obj: new OpenFile(entry, fdflags) };
+  }
+
+  path_create_directory(path) {
+    let { ret, parent, name } = this.path_lookup(path);
+    if (ret !== ERRNO_SUCCESS) {
+      return ret;
+    } else if (name === '.' || parent.entry(name) !== null) {
+      return ERRNO_EXIST;
+    } else if (name === '__proto__') {
+      return ERRNO_INVAL;
+    }
+    parent.contents[name] = new Directory({});
+    return ERRNO_SUCCESS;
+  }
+
+  path_filestat_get(path) {
+    let { ret, entry } = this.path_entry(path);
+    return { ret, filestat: entry?.stat() ?? null };
+  }
+
+  path_remove(path, directory) {
+    let { ret, parent, name } = this.path_lookup(path);
+    if (ret !== ERRNO_SUCCESS) {
+      return ret;
+    } else if (name === '.') {
+      return ERRNO_INVAL;
+    }
+
+    let entry = parent.entry(name);
+    if (entry === null) {
+      return ERRNO_NOENT;
+    } else if (directory && !(entry instanceof Directory)) {
+      return ERRNO_NOTDIR;
+    } else if (!directory && entry instanceof Directory) {
+      return ERRNO_ISDIR;
+    } else if (directory && Object.keys(entry.contents).length) {
+      return ERRNO_NOTEMPTY;
+    }
+    delete parent.contents[name];
+    return ERRNO_SUCCESS;
+  }
+}
+
+class PreopenDirectory extends OpenDirectory {
+  constructor(name, contents) {
+    super(new Directory(contents));
+    this.prestat_name = new TextEncoder().encode(name);
+  }
+
+  fd_prestat_get() {
+    return { ret: ERRNO_SUCCESS, prestat: { name: this.prestat_name } };
+  }
+}
+
+class WASI {
+  constructor(args, env, fds) {
+    this.args = args;
+    this.env = env;
+    this.fds = fds;
+    this.inst = null;
+
+    let self = this;
+    let memory = () => self.inst.exports.memory.buffer;
+    let view = () => new DataView(memory());
+    let view8 = () => new Uint8Array(memory());
+
+    let iovecs = (ptr, len) => {
+      let data = view();
+      return Array.from({ length: len }, (_, idx) => ({
+        buf: data.getUint32(ptr + 8 * idx, true),
+        buf_len: data.getUint32(ptr + 8 * idx + 4, true),
+      }));
+    };
+
+    let string = (ptr, len) => new TextDecoder().decode(view8().subarray(ptr, ptr + len));
+    let fd = (idx) => self.fds[idx] ?? null;
+
+    /* Strings as the NUL terminated UTF-8 that argv and environ point to. */
+    let encoded = (strings) => strings.map(s => new TextEncoder().encode(s + '\0'));
+    let sizes_get = (strings, count_ptr, size_ptr) => {
+      let data = view();
+      let buffers = encoded(strings);
+      data.setUint32(count_ptr, buffers.length, true);
+      data.setUint32(size_ptr, buffers.reduce((sum, buf) => sum + buf.length, 0), true);
+      return ERRNO_SUCCESS;
+    };
+    let strings_get = (strings, ptrs, buf) => {
+      let data = view();
+      for (let [idx, encoded_string] of encoded(strings).entries()) {
+        data.setUint32(ptrs + 4 * idx, buf, true);
+        view8().set(encoded_string, buf);
+        buf += encoded_string.length;
+      }
+      return ERRNO_SUCCESS;
+    };
+
+    let filestat_set = (ptr, filestat) => {
+      let data = view();
+      view8().fill(0, ptr, ptr + 64);
+      data.setUint8(ptr + 16, filestat.filetype);
+      data.setBigUint64(ptr + 24, 1n, true);
+      data.setBigUint64(ptr + 32, filestat.size, true);
+    };
+
+    let with_fd = (idx, call) => {
+      let file = fd(idx);
+      return file === null ? ERRNO_BADF : call(file);
+    };
+
+    this.wasiImport = {
+      args_get: (argv, argv_buf) => strings_get(self.args, argv, argv_buf),
+      args_sizes_get: (argc, argv_buf_size) => sizes_get(self.args, argc, argv_buf_size),
+      environ_get: (environ, environ_buf) => strings_get(self.env, environ, environ_buf),
+      environ_sizes_get: (count, size) => sizes_get(self.env, count, size),
+
+      clock_res_get(id, res_ptr) {
+        view().setBigUint64(res_ptr, id === CLOCKID_REALTIME ? 1000000n : 1000n, true);
+        return ERRNO_SUCCESS;
+      },
+      clock_time_get(id, precision, time_ptr) {
+        let nanos = id === CLOCKID_REALTIME
+          ? BigInt(Date.now()) * 1000000n
+          : BigInt(Math.round(performance.now() * 1000000));
+        view().setBigUint64(time_ptr, nanos, true);
+        return ERRNO_SUCCESS;
+      },
+
+      fd_advise: () => ERRNO_SUCCESS,
+      fd_allocate: (idx, offset, len) => with_fd(idx, file => {
+        let end = Number(offset + len);
+        return file instanceof OpenFile && end > file.file.size
+          ? file.fd_filestat_set_size(end)
+          : ERRNO_SUCCESS;
+      }),
+      fd_close(idx) {
+        return with_fd(idx, file => {
+          delete self.fds[idx];
+          return file.fd_close();
+        });
+      },
+      fd_datasync: (idx) => with_fd(idx, () => ERRNO_SUCCESS),
+      fd_sync: (idx) => with_fd(idx, () => ERRNO_SUCCESS),
+      fd_fdstat_get: (idx, ptr) => with_fd(idx, file => {
+        let { ret, fdstat } = file.fd_fdstat_get();
+        if (fdstat !== null) {
+          let data = view();
+          view8().fill(0, ptr, ptr + 24);
+          data.setUint8(ptr, fdstat.filetype);
+          data.setUint16(ptr + 2, fdstat.flags, true);
+          data.setBigUint64(ptr + 8, RIGHTS_ALL, true);
+          data.setBigUint64(ptr + 16, RIGHTS_ALL, true);
+        }
+        return ret;
+      }),
+      fd_fdstat_set_flags: (idx, flags) => with_fd(idx, file => {
+        if (file instanceof OpenFile) {
+          file.fdflags = flags;
+        }
+        return ERRNO_SUCCESS;
+      }),
+      fd_fdstat_set_rights: (idx) => with_fd(idx, () => ERRNO_SUCCESS),
+      fd_filestat_get: (idx, ptr) => with_fd(idx, file => {
+        let { ret, filestat } = file.fd_filestat_get();
+        if (filestat !== null) {
+          filestat_set(ptr, filestat);
+        }
+        return ret;
+      }),
+      fd_filestat_set_size: (idx, size) => with_fd(idx, file => file.fd_filestat_set_size(size)),
+      fd_filestat_set_times: (idx) => with_fd(idx, () => ERRNO_SUCCESS),
+      fd_pread: (idx, iovs_ptr, iovs_len, offset, nread_ptr) => with_fd(idx, file => {
+        let { ret, nread } = file.fd_pread(view8(), iovecs(iovs_ptr, iovs_len), offset);
+        view().setUint32(nread_ptr, nread, true);
+        return ret;
+      }),
+      fd_prestat_get: (idx, ptr) => with_fd(idx, file => {
+        let { ret, prestat } = file.fd_prestat_get();
+        if (prestat !== null) {
+          let data = view();
+          data.setUint32(ptr, 0, true);
+          data.setUint32(ptr + 4, prestat.name.length, true);
+        }
+        return ret;
+      }),
+      fd_prestat_dir_name: (idx, path_ptr, path_len) => with_fd(idx, file => {
+        let { ret, prestat } = file.fd_prestat_get();
+        if (prestat !== null) {
+          view8().set(prestat.name.subarray(0, path_len), path_ptr);
+        }
+        return ret;
+      }),
+      fd_pwrite: (idx, iovs_ptr, iovs_len, offset, nwritten_ptr) => with_fd(idx, file => {
+        let { ret, nwritten } = file.fd_pwrite(view8(), iovecs(iovs_ptr, iovs_len), offset);
+        view().setUint32(nwritten_ptr, nwritten, true);
+        return ret;
+      }),
+      fd_read: (idx, iovs_ptr, iovs_len, nread_ptr) => with_fd(idx, file => {
+        let { ret, nread } = file.fd_read(view8(), iovecs(iovs_ptr, iovs_len));
+        view().setUint32(nread_ptr, nread, true);
+        return ret;
+      }),
+      fd_readdir: (idx, buf, buf_len, cookie, bufused_ptr) => with_fd(idx, file => {
+        let { ret, entries } = file.fd_readdir_entries();
+        if (entries === null) {
+          return ret;
+        }
+
+        /* Entries as far as they fit, a truncated last one tells there are more. */
+        let bytes = [];
+        for (let next = Number(cookie); next < entries.length; next++) {
+          let [name, entry] = entries[next];
+          let encoded_name = new TextEncoder().encode(name);
+          let dirent = new DataView(new ArrayBuffer(24));
+          dirent.setBigUint64(0, BigInt(next + 1), true);
+          dirent.setUint32(16, encoded_name.length, true);
+          dirent.setUint8(20, entry.stat().filetype);
+          bytes.push(...new Uint8Array(dirent.buffer), ...encoded_name);
+          if (bytes.length >= buf_len) {
+            break;
+          }
+        }
+
+        let used = Math.min(bytes.length, buf_len);
+        view8().set(bytes.slice(0, used), buf);
+        view().setUint32(bufused_ptr, used, true);
+        return ERRNO_SUCCESS;
+      }),
+      fd_renumber(from, to) {
+        return with_fd(from, file => {
+          self.fds[to] = file;
+          delete self.fds[from];
+          return ERRNO_SUCCESS;
+        });
+      },
+      fd_seek: (idx, offset, whence, offset_ptr) => with_fd(idx, file => {
+        let result = file.fd_seek(offset, whence);
+        view().setBigUint64(offset_ptr, result.offset, true);
+        return result.ret;
+      }),
+      fd_tell: (idx, offset_ptr) => with_fd(idx, file => {
+        let result = file.fd_seek(0n, WHENCE_CUR);
+        view().setBigUint64(offset_ptr, result.offset, true);
+        return result.ret;
+      }),
+      fd_write: (idx, iovs_ptr, iovs_len, nwritten_ptr) => with_fd(idx, file => {
+        let { ret, nwritten } = file.fd_write(view8(), iovecs(iovs_ptr, iovs_len));
+        view().setUint32(nwritten_ptr, nwritten, true);
+        return ret;
+      }),
+
+      path_create_directory: (idx, path_ptr, path_len) =>
+        with_fd(idx, dir => dir.path_create_directory(string(path_ptr, path_len))),
+      path_filestat_get: (idx, flags, path_ptr, path_len, ptr) => with_fd(idx, dir => {
+        let { ret, filestat } = dir.path_filestat_get(string(path_ptr, path_len));
+        if (filestat !== null) {
+          filestat_set(ptr, filestat);
+        }
+        return ret;
+      }),
+      path_filestat_set_times: (idx) => with_fd(idx, () => ERRNO_SUCCESS),
+      path_link: () => ERRNO_NOTSUP,
+      path_open(idx, dirflags, path_ptr, path_len, oflags, base, inheriting, fdflags, fd_ptr) {
+        return with_fd(idx, dir => {
+          let path = string(path_ptr, path_len);
+          let { ret, fd_obj } = dir.path_open(dirflags, path, oflags, base, inheriting, fdflags);
+          if (fd_obj !== null) {
+            let free = self.fds.length;
+            self.fds[free] = fd_obj;
+            view().setUint32(fd_ptr, free, true);
+          }
+          return ret;
+        });
+      },
+      path_readlink: (idx) => with_fd(idx, () => ERRNO_INVAL),
+      path_remove_directory: (idx, path_ptr, path_len) =>
+        with_fd(idx, dir => dir.path_remove(string(path_ptr, path_len), true)),
+      path_rename(idx, old_ptr, old_len, new_idx, new_ptr, new_len) {
+        let [from, to] = [fd(idx), fd(new_idx)];
+        if (from === null || to === null) {
+          return ERRNO_BADF;
+        }
+
+        let source = from.path_lookup(string(old_ptr, old_len));
+        let target = to.path_lookup(string(new_ptr, new_len));
+        if (source.ret !== ERRNO_SUCCESS) {
+          return source.ret;
+        } else if (target.ret !== ERRNO_SUCCESS) {
+          return target.ret;
+        }
+
+        let entry = source.parent.entry(source.name);
+        if (entry === null) {
+          return ERRNO_NOENT;
+        } else if (target.name === '.' || target.name === '__proto__') {
+          return ERRNO_INVAL;
+        }
+        delete source.parent.contents[source.name];
+        target.parent.contents[target.name] = entry;
+        return ERRNO_SUCCESS;
+      },
+      path_symlink: () => ERRNO_NOTSUP,
+      path_unlink_file: (idx, path_ptr, path_len) =>
+        with_fd(idx, dir => dir.path_remove(string(path_ptr, path_len), false)),
+
+      poll_oneoff: () => ERRNO_NOTSUP,
+      proc_exit(code) {
+        throw new WASIProcExit(code);
+      },
+      proc_raise: () => ERRNO_NOSYS,
+      random_get(buf, len) {
+        /* At most 64KiB per call of `getRandomValues`. */
+        for (let at = buf; at < buf + len; at += 65536) {
+          crypto.getRandomValues(view8().subarray(at, Math.min(at + 65536, buf + len)));
+        }
+        return ERRNO_SUCCESS;
+      },
+      sched_yield: () => ERRNO_SUCCESS,
+      sock_accept: () => ERRNO_NOSYS,
+      sock_recv: () => ERRNO_NOSYS,
+      sock_send: () => ERRNO_NOSYS,
+      sock_shutdown: () => ERRNO_NOSYS,
+    };
+  }
+
+  /* Run a command, returning its exit code. */
+  start(instance) {
+    this.inst = instance;
+    try {
+      instance.exports._start();
+      return 0;
+    } catch (e) {
+      if (e instanceof WASIProcExit) {
+        return e.code;
+      }
+      throw e;
+    }
+  }
+
+  /* Set up a reactor, which exports functions instead of a command. */
+  initialize(instance) {
+    this.inst = instance;
+    instance.exports._initialize?.();
+  }
+}
@@ -0,0 +1,156 @@
+/* Tests of the syscalls that `wasi-shim.js` implements, run with `npm test`.
+ *
+ * The shim is a script, so it is evaluated here for its classes. A program's
+ * memory is stood in by a plain `WebAssembly.Memory`.
+ */
+import assert from 'node:assert/strict';
+import fs from 'node:fs';
+import { test } from 'node:test';
+
+const source = fs.readFileSync(new URL('./wasi-shim.js', import.meta.url), 'utf8');
+const { WASI, File, Directory, OpenFile, PreopenDirectory } = new Function(
+  source + '\nreturn { WASI, File, Directory, OpenFile, PreopenDirectory };'
+)();
+
+const ERRNO_SUCCESS = 0;
+const ERRNO_NOENT = 44;
+const ERRNO_NOTCAPABLE = 76;
+const OFLAGS_CREAT = 1;
+
+function instance(args, env, fds) {
+  let wasi = new WASI(args, env, fds);
+  let memory = new WebAssembly.Memory({ initial: 1 });
+  wasi.inst = { exports: { memory } };
+  let view = new DataView(memory.buffer);
+  let view8 = new Uint8Array(memory.buffer);
+  return { wasi, sys: wasi.wasiImport, view, view8 };
+}
+
+function string(view8, ptr, len) {
+  return new TextDecoder().decode(view8.subarray(ptr, ptr + len));
+}
+
+/* The NUL terminated strings that a table of `count` pointers points to. */
+function strings(view, view8, ptrs, count) {
+  return Array.from({ length: count }, (_, idx) => {
+    let start = view.getUint32(ptrs + 4 * idx, true);
+    return string(view8, start, view8.indexOf(0, start) - start);
+  });
+}
+
+function write_string(view8, ptr, text) {
+  let bytes = new TextEncoder().encode(text);
+  view8.set(bytes, ptr);
+  return bytes.length;
+}
+
+function write_iovecs(view, ptr, iovs) {
+  for (let [idx, [buf, buf_len]] of iovs.entries()) {
+    view.setUint32(ptr + 8 * idx, buf, true);
+    view.setUint32(ptr + 8 * idx + 4, buf_len, true);
+  }
+}
+
+test('args are NUL terminated UTF-8 behind a pointer table', () => {
+  let args = ['app', '--name', 'sä'];
+  let { sys, view, view8 } = instance(args, [], []);
+
+  assert.equal(sys.args_sizes_get(0, 4), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(0, true), 3);
+  assert.equal(view.getUint32(4, true), 'app\0--name\0sä\0'.length + 1);
+
+  assert.equal(sys.args_get(16, 64), ERRNO_SUCCESS);
+  assert.deepEqual(strings(view, view8, 16, 3), args);
+  assert.equal(view.getUint32(16, true), 64);
+});
+
+test('environ lists the variables as given', () => {
+  let env = ['HOME=/', 'LANG=C.UTF-8'];
+  let { sys, view, view8 } = instance([], env, []);
+
+  assert.equal(sys.environ_sizes_get(0, 4), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(0, true), 2);
+  assert.equal(view.getUint32(4, true), 'HOME=/\0LANG=C.UTF-8\0'.length);
+
+  assert.equal(sys.environ_get(16, 64), ERRNO_SUCCESS);
+  assert.deepEqual(strings(view, view8, 16, 2), env);
+});
+
+test('fd_write gathers all buffers into the file', () => {
+  let stdout = new File([]);
+  let { sys, view, view8 } = instance([], [], [new OpenFile(new File([])), new OpenFile(stdout)]);
+
+  let first = write_string(view8, 100, 'hello, ');
+  let second = write_string(view8, 200, 'world');
+  write_iovecs(view, 0, [[100, first], [200, second]]);
+  assert.equal(sys.fd_write(1, 0, 2, 32), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(32, true), first + second);
+  assert.equal(new TextDecoder().decode(stdout.data), 'hello, world');
+
+  write_iovecs(view, 0, [[200, second]]);
+  assert.equal(sys.fd_write(1, 0, 1, 32), ERRNO_SUCCESS);
+  assert.equal(new TextDecoder().decode(stdout.data), 'hello, worldworld');
+});
+
+test('fd_read scatters the file and stops at its end', () => {
+  let stdin = new File(new TextEncoder().encode('abcdef'));
+  let { sys, view, view8 } = instance([], [], [new OpenFile(stdin)]);
+
+  write_iovecs(view, 0, [[100, 2], [200, 3]]);
+  assert.equal(sys.fd_read(0, 0, 2, 32), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(32, true), 5);
+  assert.equal(string(view8, 100, 2), 'ab');
+  assert.equal(string(view8, 200, 3), 'cde');
+
+  assert.equal(sys.fd_read(0, 0, 2, 32), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(32, true), 1);
+  assert.equal(string(view8, 100, 1), 'f');
+
+  assert.equal(sys.fd_read(0, 0, 2, 32), ERRNO_SUCCESS);
+  assert.equal(view.getUint32(32, true), 0);
+});
+
+test('path_open opens files below a preopened directory', () => {
+  let config = new File(new TextEncoder().encode('[args]'));
+  let root = new PreopenDirectory('/', { etc: new Directory({ 'config.toml': config }) });
+  let fds = [new OpenFile(new File([])), new OpenFile(new File([])), new OpenFile(new File([])), root];
+  let { sys, view, view8 } = instance([], [], fds);
+
+  let len = write_string(view8, 100, 'etc/./config.toml');
+  assert.equal(sys.path_open(3, 0, 100, len, 0, 0n, 0n, 0, 0), ERRNO_SUCCESS);
+  let fd = view.getUint32(0, true);
+  assert.equal(fd, 4);
+
+  write_iovecs(view, 8, [[200, 16]]);
+  assert.equal(sys.fd_read(fd, 8, 1, 32), ERRNO_SUCCESS);
+  assert.equal(string(view8, 200, view.getUint32(32, true)), '[args]');
+});
+
+test('path_open refuses missing files and paths outside the directory', () => {
+  let root = new PreopenDirectory('/', { etc: new Directory({}) });
+  let { wasi, sys, view, view8 } = instance([], [], [root]);
+
+  view.setUint32(0, 0xffffffff, true);
+  let len = write_string(view8, 100, 'etc/missing');
+  assert.equal(sys.path_open(0, 0, 100, len, 0, 0n, 0n, 0, 0), ERRNO_NOENT);
+  len = write_string(view8, 100, 'etc/../../secret');
+  assert.equal(sys.path_open(0, 0, 100, len, 0, 0n, 0n, 0, 0), ERRNO_NOTCAPABLE);
+
+  assert.equal(view.getUint32(0, true), 0xffffffff);
+  assert.equal(wasi.fds.length, 1);
+});
+
+test('path_open creates files that are then visible to the loader', () => {
+  let etc = new Directory({});
+  let { sys, view, view8 } = instance([], [], [new PreopenDirectory('/', { etc })]);
+
+  let len = write_string(view8, 100, 'etc/out.txt');
+  assert.equal(sys.path_open(0, 0, 100, len, OFLAGS_CREAT, 0n, 0n, 0, 0), ERRNO_SUCCESS);
+  let fd = view.getUint32(0, true);
+
+  let written = write_string(view8, 200, 'saved');
+  write_iovecs(view, 8, [[200, written]]);
+  assert.equal(sys.fd_write(fd, 8, 1, 32), ERRNO_SUCCESS);
+  assert.ok(etc.entry('out.txt') instanceof File);
+  assert.equal(new TextDecoder().decode(etc.entry('out.txt').data), 'saved');
+});